@@ -0,0 +1,139 @@
+use std::fs::{read, read_to_string, write};
+use std::path::PathBuf;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use chipo::{
+    compile_file_with_symbols,
+    emu::{Clock, Machine, Movie, Proc, Quirks},
+    error::{ChipoError, Result},
+    files::FileSystem,
+    palette::Palette,
+    screenshot::write_png,
+};
+
+use chipo_headless::{dump, parse_key_script, run, KeyPress, Options};
+
+#[derive(StructOpt)]
+struct Opt {
+    #[structopt(long, short)]
+    file: PathBuf,
+
+    /// Stop after this many instructions
+    #[structopt(long, short)]
+    cycles: Option<usize>,
+
+    /// Stop after this many frames, at 60 frames per second
+    #[structopt(long)]
+    frames: Option<usize>,
+
+    /// Instructions executed per second
+    #[structopt(long, default_value = "600")]
+    ips: u64,
+
+    /// Key press as <frame>:<key>[:<frames>], with the key in hexadecimal
+    #[structopt(long = "key", short)]
+    keys: Vec<KeyPress>,
+
+    /// File with one key press per line
+    #[structopt(long)]
+    key_script: Option<PathBuf>,
+
+    /// Quirks profile: default, vip, chip-48, schip or xo-chip
+    #[structopt(long, default_value = "default")]
+    quirks: Quirks,
+
+    /// Seed of the random number generator
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+
+    /// Write the final screen to a PNG file
+    #[structopt(long)]
+    png: Option<PathBuf>,
+
+    /// Size of a high resolution pixel in the PNG file, 128x64 pixels large
+    #[structopt(long, default_value = "4")]
+    scale: usize,
+
+    /// Colors of the PNG file: chipo, neon, mono, octo, gameboy, amber, or four hexadecimal RGB
+    /// values, the background then the planes
+    #[structopt(long, default_value = "chipo")]
+    palette: Palette,
+
+    /// Palette configuration file, applied over --palette
+    #[structopt(long)]
+    palette_file: Option<PathBuf>,
+
+    /// Save the key presses to a movie file
+    #[structopt(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay a movie file, with its seed, quirks and speed, until its end
+    #[structopt(long)]
+    replay: Option<PathBuf>,
+}
+
+fn read_from_file(file: &PathBuf) -> Result<Vec<u8>> {
+    match file.extension().and_then(std::ffi::OsStr::to_str) {
+        Some("s") => compile_file_with_symbols(&file.to_string_lossy(), &FileSystem)
+            .map(|(tokens, _)| tokens),
+        Some("c8") | Some("ch8") => read(file).map_err(ChipoError::IOError),
+        _ => Err(ChipoError::InvalidFile(file.to_str().unwrap().to_string())),
+    }
+}
+
+fn try_main(args: &Opt) -> Result<()> {
+    let tokens = read_from_file(&args.file)?;
+    let mut keys = args.keys.clone();
+    if let Some(script) = &args.key_script {
+        keys.extend(parse_key_script(&read_to_string(script)?)?);
+    }
+    let mut options = Options {
+        cycles: args.cycles,
+        frames: args.frames,
+        keys,
+        replay: None,
+    };
+
+    let mut machine = match &args.replay {
+        Some(path) => {
+            let movie = read_to_string(path)?.parse::<Movie>()?;
+            let (proc, clock) = movie.start(&tokens)?;
+            options.frames = options.frames.or(Some(movie.frames as usize));
+            options.replay = Some(movie);
+            Machine::new(proc, clock)
+        }
+        None => {
+            let mut proc = Proc::with_quirks(&tokens, args.quirks)?;
+            proc.set_seed(args.seed);
+            Machine::new(proc, Clock::new(args.ips))
+        }
+    };
+    let mut movie = Movie::new(&tokens, machine.proc(), machine.clock());
+
+    let report = run(&mut machine, &options)?;
+    let proc = machine.proc();
+    print!("{}", dump(proc, &report));
+    if let Some(path) = &args.record {
+        movie.events = report.events;
+        movie.finish(report.frames as u64);
+        write(path, movie.to_string())?;
+    }
+    if let Some(path) = &args.png {
+        let mut palette = args.palette;
+        if let Some(file) = &args.palette_file {
+            palette.configure(&read_to_string(file)?)?;
+        }
+        write_png(proc, path, args.scale.max(1), &palette)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Opt::from_args();
+    if let Err(err) = try_main(&args) {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+}
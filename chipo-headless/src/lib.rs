@@ -0,0 +1,278 @@
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+use chipo::{
+    emu::{crc32, KeyEvent, Machine, MachineState, Movie, Proc, ProgramState},
+    error::{ChipoError, Result},
+};
+
+// Key of the keypad held down from a frame for a number of frames,
+// written <frame>:<key>[:<frames>] with the key in hexadecimal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub frame: usize,
+    pub key: usize,
+    pub frames: usize,
+}
+
+impl FromStr for KeyPress {
+    type Err = ChipoError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || ChipoError::InvalidKeyPress(s.to_string());
+        let fields = s.trim().split(':').collect::<Vec<&str>>();
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(invalid());
+        }
+        let key = usize::from_str_radix(fields[1], 16).map_err(|_| invalid())?;
+        if key > 0xF {
+            return Err(invalid());
+        }
+        let frames = match fields.get(2) {
+            Some(frames) => frames.parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+        // A key is held for at least one frame, or it would never be released
+        if frames == 0 {
+            return Err(invalid());
+        }
+        Ok(KeyPress {
+            frame: fields[0].parse().map_err(|_| invalid())?,
+            key,
+            frames,
+        })
+    }
+}
+
+// Reads key presses from a script with one press per line, # starts a comment
+pub fn parse_key_script(script: &str) -> Result<Vec<KeyPress>> {
+    script
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(KeyPress::from_str)
+        .collect()
+}
+
+pub struct Options {
+    pub cycles: Option<usize>,
+    pub frames: Option<usize>,
+    pub keys: Vec<KeyPress>,
+    // Movie whose key events are replayed instead of the key presses
+    pub replay: Option<Movie>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopReason {
+    ProgramStopped,
+    CycleLimit,
+    FrameLimit,
+}
+
+pub struct Report {
+    pub cycles: usize,
+    pub frames: usize,
+    pub reason: StopReason,
+    // Key events applied, to save as a movie
+    pub events: Vec<KeyEvent>,
+}
+
+// Runs the program without any display until it stops or reaches a limit
+pub fn run(machine: &mut Machine, options: &Options) -> Result<Report> {
+    let mut report = Report {
+        cycles: 0,
+        frames: 0,
+        reason: StopReason::ProgramStopped,
+        events: vec![],
+    };
+    if let Some(movie) = &options.replay {
+        machine.start_replay(movie.replay());
+    }
+    loop {
+        let frame = report.frames;
+        if !machine.is_replaying() {
+            for press in &options.keys {
+                let down = match frame {
+                    f if f == press.frame => true,
+                    f if f == press.frame + press.frames => false,
+                    _ => continue,
+                };
+                machine.set_key(press.key, down);
+                report.events.push(KeyEvent {
+                    frame: frame as u64,
+                    key: press.key,
+                    down,
+                });
+            }
+        }
+
+        let mut cycle_limit = false;
+        let state = machine.frame(|proc| {
+            if Some(report.cycles) == options.cycles {
+                cycle_limit = true;
+                return Ok(ProgramState::Stop);
+            }
+            report.cycles += 1;
+            proc.cycle()
+        })?;
+        if cycle_limit {
+            report.reason = StopReason::CycleLimit;
+            return Ok(report);
+        }
+        if let MachineState::Stopped = state {
+            return Ok(report);
+        }
+        report.frames += 1;
+
+        if Some(report.frames) == options.frames {
+            report.reason = StopReason::FrameLimit;
+            return Ok(report);
+        }
+    }
+}
+
+pub fn memory_hash(proc: &Proc) -> u32 {
+    crc32(proc.memory())
+}
+
+// Text dump of the final state of the processor, with the screen drawn
+// using one character per pixel
+pub fn dump(proc: &Proc, report: &Report) -> String {
+    let mut out = String::new();
+    let reason = match report.reason {
+        StopReason::ProgramStopped => "program stopped",
+        StopReason::CycleLimit => "cycle limit",
+        StopReason::FrameLimit => "frame limit",
+    };
+    writeln!(
+        out,
+        "stopped after {} cycles and {} frames ({})",
+        report.cycles, report.frames, reason
+    )
+    .unwrap();
+    for (n, val) in proc.registers().iter().enumerate() {
+        let sep = if n % 8 == 7 { '\n' } else { ' ' };
+        write!(out, "v{:X}={:02X}{}", n, val, sep).unwrap();
+    }
+    writeln!(
+        out,
+        "i={:04X} pc={:04X} dt={:02X} st={:02X}",
+        proc.i(),
+        proc.pc(),
+        proc.delay_timer(),
+        proc.sound_timer()
+    )
+    .unwrap();
+    let stack = proc
+        .stack()
+        .iter()
+        .map(|addr| format!("{:04X}", addr))
+        .collect::<Vec<String>>();
+    writeln!(out, "stack=[{}]", stack.join(", ")).unwrap();
+    writeln!(out, "memory crc32={:08X}", memory_hash(proc)).unwrap();
+
+    let width = proc.width();
+    for row in proc.pixels[..width * proc.height()].chunks(width) {
+        let line = row
+            .iter()
+            .map(|&pixel| match pixel {
+                0 => '.',
+                1 => '#',
+                2 => '+',
+                _ => '*',
+            })
+            .collect::<String>();
+        writeln!(out, "{}", line).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chipo::{compile, emu::Clock};
+
+    #[test]
+    fn test_key_press() {
+        assert_eq!(
+            "30:a".parse::<KeyPress>().unwrap(),
+            KeyPress {
+                frame: 30,
+                key: 0xA,
+                frames: 1
+            }
+        );
+        assert_eq!("3:1:10".parse::<KeyPress>().unwrap().frames, 10);
+        assert!("3:10".parse::<KeyPress>().is_err());
+        assert!(matches!(
+            "3:1:0".parse::<KeyPress>(),
+            Err(ChipoError::InvalidKeyPress(_))
+        ));
+        assert!("3".parse::<KeyPress>().is_err());
+        assert_eq!(
+            parse_key_script("# start\n1:5 # five\n\n2:6:2\n")
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let binary = compile(
+            r#".code
+    ld v0, k
+    ld f, v0
+    drw v1, v1, 5
+    ret"#,
+        )
+        .unwrap();
+        let options = Options {
+            cycles: None,
+            frames: Some(100),
+            keys: vec!["20:7".parse().unwrap()],
+            replay: None,
+        };
+
+        let machine = || Machine::new(Proc::binary(&binary).unwrap(), Clock::new(600));
+        let mut recorded = machine();
+        let report = run(&mut recorded, &options).unwrap();
+        let proc = recorded.proc();
+        assert_eq!(report.reason, StopReason::ProgramStopped);
+        assert_eq!(report.frames, 20);
+        assert_eq!(proc.registers()[0], 7);
+        let text = dump(proc, &report);
+        assert!(text.contains("v0=07"));
+        assert!(text.contains("\n####..."));
+        assert_eq!(report.events.len(), 1);
+
+        let mut movie = Movie::new(&binary, proc, &Clock::new(600));
+        movie.events = report.events;
+        movie.finish(report.frames as u64);
+        let mut replayed = machine();
+        let replay = Options {
+            keys: vec![],
+            replay: Some(movie),
+            ..options
+        };
+        run(&mut replayed, &replay).unwrap();
+        assert_eq!(replayed.proc().registers()[0], 7);
+
+        let options = Options {
+            keys: vec![],
+            replay: None,
+            ..replay
+        };
+        let report = run(&mut machine(), &options).unwrap();
+        assert_eq!(report.reason, StopReason::FrameLimit);
+        assert_eq!(report.cycles, 1000);
+
+        let options = Options {
+            cycles: Some(15),
+            ..options
+        };
+        let report = run(&mut machine(), &options).unwrap();
+        assert_eq!(report.reason, StopReason::CycleLimit);
+        assert_eq!((report.cycles, report.frames), (15, 1));
+    }
+}
@@ -5,12 +5,21 @@ use colorful::Colorful;
 use structopt::StructOpt;
 
 use chipo::{
-    compile,
+    compile_file_with_symbols,
+    emu::{Addr, Clock, Keymap, Movie, Proc, Quirks},
     error::{ChipoError, Result},
+    files::FileSystem,
+    gdb::GdbStub,
+    palette::Palette,
     reverse_parse,
 };
 
-use chipo_native::run;
+use std::collections::HashMap;
+
+use chipo_native::{
+    run, run_terminal, Capture, Debugger, Filter, Filters, Options, SaveSlots, TerminalMode,
+    DEFAULT_GAMEPAD_PROFILE,
+};
 
 #[derive(StructOpt)]
 struct Opt {
@@ -22,15 +31,94 @@ struct Opt {
 
     #[structopt(long, short)]
     out_file: Option<PathBuf>,
+
+    /// Quirks profile: default, vip, chip-48, schip or xo-chip
+    #[structopt(long, default_value = "default")]
+    quirks: Quirks,
+
+    /// Keyboard layout preset: qwerty, azerty or numpad
+    #[structopt(long, default_value = "qwerty")]
+    keymap: Keymap,
+
+    /// Keymap configuration file, applied over the preset
+    #[structopt(long)]
+    keymap_file: Option<PathBuf>,
+
+    /// Controller mapping file, applied over the default profile
+    #[structopt(long)]
+    gamepad_file: Option<PathBuf>,
+
+    /// Size of a CHIP-8 pixel in the window, which resizes with the resolution of the program
+    #[structopt(long, default_value = "10")]
+    scale: u32,
+
+    /// Color theme: chipo, neon, mono, octo, gameboy, amber, or four hexadecimal RGB values, the
+    /// background then the planes
+    #[structopt(long, default_value = "chipo")]
+    palette: Palette,
+
+    /// Palette configuration file, applied over the theme
+    #[structopt(long)]
+    palette_file: Option<PathBuf>,
+
+    /// Rendering filter: ghosting, blend, scanlines or crt, can be repeated
+    #[structopt(long = "filter")]
+    filters: Vec<Filter>,
+
+    /// Fraction of their brightness pixels lose each frame once turned off with ghosting
+    #[structopt(long, default_value = "0.25")]
+    decay: f64,
+
+    /// Size of a CHIP-8 high resolution pixel in screenshots (F9) and recordings (F10)
+    #[structopt(long, default_value = "4")]
+    capture_scale: usize,
+
+    /// Instructions executed per second, the timers always run at 60 Hz
+    #[structopt(long, default_value = "600")]
+    ips: u64,
+
+    /// Emulation speed, 0.5 for half speed or 2 for twice as fast
+    #[structopt(long, default_value = "1")]
+    speed: f64,
+
+    /// Start paused, F5 resumes and F6 advances one frame
+    #[structopt(long)]
+    paused: bool,
+
+    /// Seed of the random number generator, random when not given
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Draw the screen in the terminal with half blocks instead of opening a window
+    #[structopt(long, conflicts_with = "debug")]
+    terminal: bool,
+
+    /// Draw the screen in the terminal with braille patterns, four times smaller
+    #[structopt(long, conflicts_with = "debug")]
+    braille: bool,
+
+    /// Start paused in the step debugger, F12 breaks into it while running
+    #[structopt(long, short)]
+    debug: bool,
+
+    /// Wait for a GDB remote protocol connection on this address, like 127.0.0.1:1234
+    #[structopt(long, conflicts_with = "debug")]
+    gdb: Option<String>,
+
+    /// Record the key presses to a movie file, saved on exit
+    #[structopt(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay a movie file with its seed, quirks and speed
+    #[structopt(long)]
+    replay: Option<PathBuf>,
 }
 
-fn read_from_file(file: &PathBuf) -> Result<Vec<u8>> {
+// Reads a program and the addresses of its labels when it is an assembly file
+fn read_from_file(file: &PathBuf) -> Result<(Vec<u8>, HashMap<String, Addr>)> {
     match file.extension().and_then(std::ffi::OsStr::to_str) {
-        Some("s") => {
-            let asm = read_to_string(file)?;
-            compile(&asm)
-        }
-        Some("c8") | Some("ch8") => read(file).map_err(ChipoError::IOError),
+        Some("s") => compile_file_with_symbols(&file.to_string_lossy(), &FileSystem),
+        Some("c8") | Some("ch8") => Ok((read(file)?, HashMap::new())),
         _ => Err(ChipoError::InvalidFile(file.to_str().unwrap().to_string())),
     }
 }
@@ -53,13 +141,84 @@ fn write_to_file(file: &PathBuf, tokens: &[u8]) -> Result<()> {
 
 fn try_main(args: &Opt) -> Result<()> {
     let file = &args.file;
-    let tokens = read_from_file(file)?;
+    let (tokens, symbols) = read_from_file(file)?;
     if let Some(out_path) = &args.out_file {
         write_to_file(out_path, &tokens)?;
     }
 
     if !args.no_run {
-        run(&tokens)?;
+        let movie = match &args.replay {
+            Some(path) => Some(read_to_string(path)?.parse::<Movie>()?),
+            None => None,
+        };
+        let (mut proc, mut clock) = match &movie {
+            Some(movie) => movie.start(&tokens)?,
+            None => (
+                Proc::with_quirks(&tokens, args.quirks)?,
+                Clock::new(args.ips),
+            ),
+        };
+        if let (Some(seed), None) = (args.seed, &movie) {
+            proc.set_seed(seed);
+        }
+        println!("running with seed {}", proc.seed());
+        let slots = SaveSlots::new(Some(file.clone()));
+        let debugger = if args.debug {
+            Some(Debugger::new(symbols))
+        } else {
+            None
+        };
+        let gdb = match &args.gdb {
+            Some(addr) => {
+                println!("waiting for a debugger on {}", addr);
+                Some(GdbStub::listen(addr.as_str())?)
+            }
+            None => None,
+        };
+        let rom_name = file.file_name().and_then(std::ffi::OsStr::to_str);
+        let mut keymap = args.keymap.clone();
+        if let Some(path) = &args.keymap_file {
+            keymap.configure(&read_to_string(path)?, rom_name, &tokens)?;
+        }
+        let mut gamepad = Keymap::empty();
+        gamepad.configure(DEFAULT_GAMEPAD_PROFILE, None, &tokens)?;
+        if let Some(path) = &args.gamepad_file {
+            gamepad.configure(&read_to_string(path)?, rom_name, &tokens)?;
+        }
+        let mut palette = args.palette;
+        if let Some(path) = &args.palette_file {
+            palette.configure(&read_to_string(path)?)?;
+        }
+        clock.set_speed(args.speed);
+        clock.set_paused(args.paused);
+        let options = Options {
+            scale: args.scale.max(1),
+            slots,
+            capture: Capture::new(file, args.capture_scale, palette),
+            palette,
+            filters: Filters::new(&args.filters, args.decay),
+            debugger,
+            gdb,
+            movie: args
+                .record
+                .as_ref()
+                .map(|_| Movie::new(&tokens, &proc, &clock)),
+            replay: movie.as_ref().map(Movie::replay),
+            clock,
+            keymap,
+            gamepad,
+        };
+        let movie = if args.braille {
+            run_terminal(proc, options, TerminalMode::Braille)?
+        } else if args.terminal {
+            run_terminal(proc, options, TerminalMode::HalfBlocks)?
+        } else {
+            run(proc, options)?
+        };
+        if let (Some(movie), Some(path)) = (movie, &args.record) {
+            write(path, movie.to_string())?;
+            println!("movie saved to {}", path.display());
+        }
     }
 
     Ok(())
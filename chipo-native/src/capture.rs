@@ -0,0 +1,118 @@
+use std::fs::write;
+use std::path::{Path, PathBuf};
+
+use chipo::{
+    emu::{Proc, TIMER_FREQUENCY},
+    error::Result,
+    gif::GifEncoder,
+    palette::Palette,
+    screenshot::{screen_image, write_png, HEIGHT, WIDTH},
+};
+
+// Recordings keep one frame out of two, as GIF delays are in hundredths of
+// a second and viewers slow down shorter ones
+const RECORD_EVERY: u64 = 2;
+
+// Screenshots and recordings of the screen, written next to the rom as
+// <rom>-<n>.png and <rom>-<n>.gif without overwriting earlier ones
+pub struct Capture {
+    prefix: PathBuf,
+    scale: usize,
+    palette: Palette,
+    recording: Option<Recording>,
+}
+
+struct Recording {
+    // Distinct frames with the emulation frame they were shown from
+    frames: Vec<(Vec<u8>, u64)>,
+    width: usize,
+    height: usize,
+}
+
+// Hundredths of second from the start of the emulation to a frame
+fn centiseconds(frame: u64) -> u64 {
+    frame * 100 / TIMER_FREQUENCY
+}
+
+impl Capture {
+    pub fn new(rom: &Path, scale: usize, palette: Palette) -> Self {
+        Capture {
+            prefix: rom.with_extension(""),
+            scale: scale.max(1),
+            palette,
+            recording: None,
+        }
+    }
+
+    fn next_path(&self, extension: &str) -> PathBuf {
+        let name = self
+            .prefix
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        (1..)
+            .map(|n| {
+                self.prefix
+                    .with_file_name(format!("{}-{}.{}", name, n, extension))
+            })
+            .find(|path| !path.exists())
+            .unwrap()
+    }
+
+    pub fn screenshot(&self, proc: &Proc) -> Result<PathBuf> {
+        let path = self.next_path("png");
+        write_png(proc, &path, self.scale, &self.palette)?;
+        Ok(path)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn start_recording(&mut self) {
+        let (width, height) = (WIDTH * self.scale, HEIGHT * self.scale);
+        self.recording = Some(Recording {
+            frames: vec![],
+            width,
+            height,
+        });
+    }
+
+    // Adds the screen shown from frame to the recording, if any
+    pub fn record(&mut self, proc: &Proc, frame: u64) {
+        let scale = self.scale;
+        if let Some(recording) = self.recording.as_mut() {
+            if !frame.is_multiple_of(RECORD_EVERY) {
+                return;
+            }
+            let (_, _, pixels) = screen_image(proc, scale);
+            // Unchanged frames only make the previous one last longer
+            if recording.frames.last().map(|(last, _)| last) != Some(&pixels) {
+                recording.frames.push((pixels, frame));
+            }
+        }
+    }
+
+    // Writes the recording as a GIF, ending at frame, and returns its path
+    pub fn stop_recording(&mut self, frame: u64) -> Result<Option<PathBuf>> {
+        let recording = match self.recording.take() {
+            Some(recording) if !recording.frames.is_empty() => recording,
+            _ => return Ok(None),
+        };
+
+        let mut gif = GifEncoder::new(
+            recording.width as u16,
+            recording.height as u16,
+            self.palette.colors(),
+        );
+        let ends = recording.frames.iter().skip(1).map(|(_, start)| *start);
+        for ((pixels, start), end) in recording.frames.iter().zip(ends.chain(Some(frame))) {
+            let delay = centiseconds(end) - centiseconds(*start);
+            gif.add_frame(pixels, delay.clamp(2, u16::MAX as u64) as u16);
+        }
+
+        let path = self.next_path("gif");
+        write(&path, gif.finish())?;
+        Ok(Some(path))
+    }
+}
@@ -0,0 +1,172 @@
+use std::time::Instant;
+
+use chipo::{
+    emu::{Audio, Display, Input, Machine, MachineState, Proc, ProgramState},
+    error::Result,
+};
+
+use crate::{Capture, SaveSlots};
+
+// Speeds reached by halving and doubling with F7 and F8
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 8.0;
+
+// Keys of the window and the terminal which control the emulation instead of
+// pressing keypad keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    // F5
+    TogglePause,
+    // F6
+    AdvanceFrame,
+    // F7 and F8
+    SlowDown,
+    SpeedUp,
+    // F9 and F10
+    Screenshot,
+    ToggleRecording,
+    // Backspace, held down or released
+    Rewind(bool),
+    // Shift + F1 to F4, and F1 to F4
+    SaveState(usize),
+    LoadState(usize),
+}
+
+impl Hotkey {
+    // F<n> keys, with shift for saving states
+    pub fn function_key(n: u8, shift: bool) -> Option<Self> {
+        let hotkey = match n {
+            1..=4 if shift => Hotkey::SaveState(n as usize - 1),
+            1..=4 => Hotkey::LoadState(n as usize - 1),
+            5 => Hotkey::TogglePause,
+            6 => Hotkey::AdvanceFrame,
+            7 => Hotkey::SlowDown,
+            8 => Hotkey::SpeedUp,
+            9 => Hotkey::Screenshot,
+            10 => Hotkey::ToggleRecording,
+            _ => return None,
+        };
+        Some(hotkey)
+    }
+}
+
+// Runs the machine in real time for the window and the terminal, with the
+// hotkeys they share
+pub struct Controls {
+    slots: SaveSlots,
+    last_update: Instant,
+    advance_frame: bool,
+}
+
+impl Controls {
+    pub fn new(slots: SaveSlots) -> Self {
+        Controls {
+            slots,
+            last_update: Instant::now(),
+            advance_frame: false,
+        }
+    }
+
+    // Runs the frames due since the last update, or the frame stepped with F6
+    pub fn update(
+        &mut self,
+        machine: &mut Machine,
+        display: &mut dyn Display,
+        audio: &mut dyn Audio,
+        input: &mut dyn Input,
+        cycle: impl FnMut(&mut Proc, &mut dyn Display, &mut dyn Audio) -> Result<ProgramState>,
+    ) -> Result<MachineState> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update);
+        self.last_update = now;
+        if self.advance_frame {
+            self.advance_frame = false;
+            machine.run_with(1, display, audio, input, cycle)
+        } else {
+            machine.update_with(elapsed, display, audio, input, cycle)
+        }
+    }
+
+    // The time spent waiting, like in the debugger prompt, is not caught up
+    pub fn skip_time(&mut self) {
+        self.last_update = Instant::now();
+    }
+
+    // Applies a hotkey and returns the message telling what it did
+    pub fn hotkey(
+        &mut self,
+        hotkey: Hotkey,
+        machine: &mut Machine,
+        capture: &mut Capture,
+    ) -> Option<String> {
+        let clock = machine.clock_mut();
+        let message = match hotkey {
+            Hotkey::TogglePause => {
+                clock.set_paused(!clock.is_paused());
+                if clock.is_paused() {
+                    "paused"
+                } else {
+                    "resumed"
+                }
+                .to_string()
+            }
+            // Frames are only stepped while paused
+            Hotkey::AdvanceFrame => {
+                self.advance_frame = clock.is_paused();
+                return None;
+            }
+            Hotkey::SlowDown | Hotkey::SpeedUp => {
+                let speed = if hotkey == Hotkey::SlowDown {
+                    clock.speed() / 2.0
+                } else {
+                    clock.speed() * 2.0
+                };
+                clock.set_speed(speed.clamp(MIN_SPEED, MAX_SPEED));
+                format!("speed x{}", clock.speed())
+            }
+            Hotkey::Screenshot => match capture.screenshot(machine.proc()) {
+                Ok(path) => format!("screenshot saved to {}", path.display()),
+                Err(err) => format!("could not save screenshot: {}", err),
+            },
+            Hotkey::ToggleRecording if capture.is_recording() => {
+                match capture.stop_recording(clock.frame()) {
+                    Ok(Some(path)) => format!("recording saved to {}", path.display()),
+                    Ok(None) => "nothing was recorded".to_string(),
+                    Err(err) => format!("could not save recording: {}", err),
+                }
+            }
+            Hotkey::ToggleRecording => {
+                capture.start_recording();
+                "recording the screen, F10 stops".to_string()
+            }
+            Hotkey::Rewind(rewinding) => {
+                machine.set_rewinding(rewinding);
+                return None;
+            }
+            Hotkey::SaveState(slot) => match self.slots.save(slot, machine.proc()) {
+                Ok(()) => format!("state saved to slot {}", slot + 1),
+                Err(err) => format!("could not save state: {}", err),
+            },
+            Hotkey::LoadState(_) if machine.has_movie() => {
+                "states can't be loaded with a movie".to_string()
+            }
+            Hotkey::LoadState(slot) => match self.slots.load(slot, machine.proc_mut()) {
+                Ok(()) => {
+                    machine.clear_rewind();
+                    format!("state loaded from slot {}", slot + 1)
+                }
+                Err(err) => format!("could not load state: {}", err),
+            },
+        };
+        Some(message)
+    }
+}
+
+// Message telling why the machine stopped running normally, if it did
+pub fn state_message(state: MachineState) -> Option<String> {
+    match state {
+        MachineState::Running | MachineState::Stopped => None,
+        MachineState::Watchpoint(watchpoint) => Some(format!("watchpoint reached: {}", watchpoint)),
+        MachineState::ReplayFinished(frame) => Some(format!("replay finished at frame {}", frame)),
+    }
+}
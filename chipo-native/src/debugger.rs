@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{stdin, stdout, Write};
+
+use chipo::emu::{Addr, Proc, Watchpoint};
+
+const HELP: &str = r#"commands:
+  s, step [n]        execute n instructions (default 1)
+  c, continue        run until the next breakpoint
+  b, break <addr>    set a breakpoint on an address or a label
+  d, delete <addr>   remove a breakpoint
+  w, watch <addr> [len]
+                     stop on writes to memory
+  w, watch <vx|i> <value>
+                     stop when a register takes a value
+  dw <n>             remove the watchpoint numbered n by list
+  l, list            list breakpoints and watchpoints
+  r, regs            show registers, timers and stack
+  x, disas [addr]    disassemble around pc or addr
+  q, quit            stop the emulator"#;
+
+pub enum DebuggerAction {
+    Continue,
+    Quit,
+}
+
+enum Mode {
+    Paused,
+    Step(usize),
+    Running,
+}
+
+// Step debugger driven from the terminal while the program runs in the window
+pub struct Debugger {
+    symbols: HashMap<String, Addr>,
+    // The alphabetically first label of each address, for stable listings
+    labels: BTreeMap<usize, String>,
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+    // Address of the breakpoint execution was resumed from, so it doesn't trigger again
+    resumed_from: Option<usize>,
+}
+
+impl Debugger {
+    pub fn new(symbols: HashMap<String, Addr>) -> Self {
+        let mut labels = BTreeMap::new();
+        for (name, &addr) in &symbols {
+            let label = labels.entry(addr as usize).or_insert_with(|| name.clone());
+            if name < label {
+                *label = name.clone();
+            }
+        }
+        Debugger {
+            symbols,
+            labels,
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Paused,
+            resumed_from: None,
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    // Called before each cycle, true when execution should stop and prompt
+    pub fn should_break(&mut self, proc: &Proc) -> bool {
+        let pc = proc.pc();
+        match self.mode {
+            Mode::Running => {
+                if self.breakpoints.contains(&pc) && self.resumed_from != Some(pc) {
+                    println!("breakpoint at {}", self.describe(pc));
+                    self.mode = Mode::Paused;
+                }
+            }
+            Mode::Step(0) => self.mode = Mode::Paused,
+            Mode::Step(n) => self.mode = Mode::Step(n - 1),
+            Mode::Paused => {}
+        }
+        self.resumed_from = None;
+        matches!(self.mode, Mode::Paused)
+    }
+
+    // Reads commands from stdin until execution is resumed
+    pub fn prompt(&mut self, proc: &mut Proc) -> DebuggerAction {
+        self.print_registers(proc);
+        self.print_disassembly(proc, proc.pc());
+        loop {
+            print!("(chipo) ");
+            stdout().flush().ok();
+            let mut line = String::new();
+            match stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => return DebuggerAction::Quit,
+                Ok(_) => {}
+            }
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("s");
+            let arg = words.next();
+            let value = words.next();
+
+            match command {
+                "s" | "step" => {
+                    let n = arg.and_then(|n| n.parse::<usize>().ok()).unwrap_or(1);
+                    // The current instruction is executed right after the prompt
+                    self.mode = Mode::Step(n.max(1) - 1);
+                    self.resumed_from = Some(proc.pc());
+                    return DebuggerAction::Continue;
+                }
+                "c" | "continue" => {
+                    self.mode = Mode::Running;
+                    self.resumed_from = Some(proc.pc());
+                    return DebuggerAction::Continue;
+                }
+                "b" | "break" => match arg.map(|arg| self.resolve(arg)) {
+                    Some(Some(addr)) => {
+                        self.breakpoints.insert(addr);
+                        println!("breakpoint set at {}", self.describe(addr));
+                    }
+                    _ => println!("expected an address or a label"),
+                },
+                "d" | "delete" => match arg.map(|arg| self.resolve(arg)) {
+                    Some(Some(addr)) if self.breakpoints.remove(&addr) => {
+                        println!("breakpoint removed at {}", self.describe(addr));
+                    }
+                    _ => println!("no such breakpoint"),
+                },
+                "w" | "watch" => match self.watchpoint(arg, value) {
+                    Some(watchpoint) => {
+                        proc.add_watchpoint(watchpoint);
+                        println!("watching {}", watchpoint);
+                    }
+                    None => println!("expected an address, a register or i"),
+                },
+                "dw" => {
+                    let watchpoint = arg
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .and_then(|n| proc.watchpoints().get(n).copied());
+                    match watchpoint {
+                        Some(watchpoint) => {
+                            proc.remove_watchpoint(&watchpoint);
+                            println!("watchpoint removed on {}", watchpoint);
+                        }
+                        None => println!("no such watchpoint"),
+                    }
+                }
+                "l" | "list" => {
+                    for &addr in &self.breakpoints {
+                        println!("  {}", self.describe(addr));
+                    }
+                    for (n, watchpoint) in proc.watchpoints().iter().enumerate() {
+                        println!("  {}: {}", n, watchpoint);
+                    }
+                }
+                "r" | "regs" => self.print_registers(proc),
+                "x" | "disas" => {
+                    let addr = arg.and_then(|arg| self.resolve(arg)).unwrap_or(proc.pc());
+                    self.print_disassembly(proc, addr);
+                }
+                "q" | "quit" => return DebuggerAction::Quit,
+                _ => println!("{}", HELP),
+            }
+        }
+    }
+
+    fn resolve(&self, symbol: &str) -> Option<usize> {
+        if let Some(&addr) = self.symbols.get(symbol) {
+            return Some(addr as usize);
+        }
+        if let Some(hex) = symbol.strip_prefix("0x") {
+            usize::from_str_radix(hex, 16).ok()
+        } else {
+            symbol.parse::<usize>().ok()
+        }
+    }
+
+    fn watchpoint(&self, target: Option<&str>, value: Option<&str>) -> Option<Watchpoint> {
+        let target = target?;
+        let value = value.map(|value| self.resolve(value));
+        // Labels take precedence, so that `velocity` or `i` can name memory
+        if !self.symbols.contains_key(target) {
+            match (target, value) {
+                ("i", Some(Some(value))) => return Some(Watchpoint::Index(value as Addr)),
+                (reg, Some(Some(value))) if value <= 0xFF => {
+                    if let Some(vx) = register(reg) {
+                        return Some(Watchpoint::Register(vx, value as u8));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let addr = self.resolve(target)?;
+        Some(Watchpoint::memory(addr, value.flatten().unwrap_or(1)))
+    }
+
+    fn label(&self, addr: usize) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    fn describe(&self, addr: usize) -> String {
+        match self.label(addr) {
+            Some(label) => format!("0x{:03X} ({})", addr, label),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    fn print_registers(&self, proc: &Proc) {
+        let registers = proc
+            .registers()
+            .iter()
+            .enumerate()
+            .map(|(i, val)| format!("v{:X}={:02X}", i, val))
+            .collect::<Vec<String>>();
+        println!("{}", registers[..8].join(" "));
+        println!("{}", registers[8..].join(" "));
+        println!(
+            "i={:04X} dt={:02X} st={:02X} pc={}",
+            proc.i(),
+            proc.delay_timer(),
+            proc.sound_timer(),
+            self.describe(proc.pc()),
+        );
+        let stack = proc
+            .stack()
+            .iter()
+            .map(|&addr| self.describe(addr as usize))
+            .collect::<Vec<String>>();
+        println!("stack: [{}]", stack.join(", "));
+    }
+
+    fn print_disassembly(&self, proc: &Proc, around: usize) {
+        let start = around.saturating_sub(6).max(0x200.min(around));
+        let end = (around + 8).min(proc.memory().len() - 4);
+        let mut addr = start;
+        while addr < end {
+            if let Some(label) = self.label(addr) {
+                println!("{}:", label);
+            }
+            let marker = if addr == proc.pc() { '>' } else { ' ' };
+            let breakpoint = if self.breakpoints.contains(&addr) {
+                '*'
+            } else {
+                ' '
+            };
+            let (asm, size) = match proc.instruction_at(addr) {
+                Ok(instr) => (instr.to_asm(), instr.size()),
+                Err(err) => (err.to_string(), 2),
+            };
+            println!("{}{} 0x{:03X}  {}", marker, breakpoint, addr, asm);
+            addr += size;
+        }
+    }
+}
+
+// Only v0 to vf name registers, anything else starting with v may be a label
+fn register(name: &str) -> Option<usize> {
+    match name.as_bytes() {
+        [b'v', digit] => (*digit as char).to_digit(16).map(|vx| vx as usize),
+        _ => None,
+    }
+}
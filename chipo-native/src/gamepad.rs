@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use sdl2::controller::GameController;
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+
+use chipo::emu::Keymap;
+
+// Default controller profile in the keymap configuration format. Buttons
+// are named after SDL (a, b, x, y, back, start, leftshoulder, dpup...) and
+// sticks and triggers by their axis followed by the direction.
+pub const DEFAULT_PROFILE: &str = "
+dpup = 2
+dpleft = 4
+dpright = 6
+dpdown = 8
+lefty- = 2
+leftx- = 4
+leftx+ = 6
+lefty+ = 8
+a = 5
+b = 0
+x = a
+y = b
+leftshoulder = 1
+rightshoulder = 3
+back = c
+start = f
+";
+
+// Sticks count as pressed past about a third of their course
+const DEADZONE: i16 = 12000;
+
+// Game controllers opened as they are plugged in, whose buttons and sticks
+// are mapped to the keypad with their own keymap
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    controllers: HashMap<i32, GameController>,
+    keymap: Keymap,
+    // Stick directions currently held, to only report changes
+    held: HashMap<(i32, String), bool>,
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem, keymap: Keymap) -> Self {
+        Gamepads {
+            subsystem,
+            controllers: HashMap::new(),
+            keymap,
+            held: HashMap::new(),
+        }
+    }
+
+    // Keypad keys pressed or released by an event, as (key, down)
+    pub fn handle(&mut self, event: &Event) -> Vec<(usize, bool)> {
+        match event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.subsystem.open(*which) {
+                    Ok(controller) => {
+                        println!("controller connected: {}", controller.name());
+                        self.controllers
+                            .insert(controller.instance_id(), controller);
+                    }
+                    Err(err) => eprintln!("could not open controller: {}", err),
+                }
+                vec![]
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(controller) = self.controllers.remove(which) {
+                    println!("controller disconnected: {}", controller.name());
+                }
+                // Release what the controller was holding
+                let released = self
+                    .held
+                    .iter()
+                    .filter(|((id, _), &down)| id == which && down)
+                    .filter_map(|((_, name), _)| self.keymap.get(name))
+                    .map(|key| (key, false))
+                    .collect();
+                self.held.retain(|(id, _), _| id != which);
+                released
+            }
+            Event::ControllerButtonDown { button, .. } => self.button(&button.string(), true),
+            Event::ControllerButtonUp { button, .. } => self.button(&button.string(), false),
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => {
+                let axis = axis.string();
+                let mut keys = self.direction(*which, format!("{}-", axis), *value < -DEADZONE);
+                keys.extend(self.direction(*which, format!("{}+", axis), *value > DEADZONE));
+                keys
+            }
+            _ => vec![],
+        }
+    }
+
+    fn button(&self, name: &str, down: bool) -> Vec<(usize, bool)> {
+        self.keymap
+            .get(name)
+            .map(|key| (key, down))
+            .into_iter()
+            .collect()
+    }
+
+    fn direction(&mut self, which: i32, name: String, down: bool) -> Vec<(usize, bool)> {
+        let key = self.keymap.get(&name);
+        let was_down = self.held.insert((which, name), down).unwrap_or(false);
+        match key {
+            Some(key) if was_down != down => vec![(key, down)],
+            _ => vec![],
+        }
+    }
+}
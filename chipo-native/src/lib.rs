@@ -1,110 +1,335 @@
+mod capture;
+mod controls;
+mod debugger;
+mod gamepad;
 mod media;
+mod states;
+mod terminal;
 
-use std::time::{Duration, Instant};
+use std::thread::sleep;
+use std::time::Duration;
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode as SDLKeycode;
-use sdl2::rect::Rect;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode as SDLKeycode, Mod};
+use sdl2::pixels::Color;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Texture, WindowCanvas};
 
 use chipo::{
-    emu::{Keycode, Proc, ProgramState},
+    emu::{
+        Clock, Display, Input, Keymap, Machine, MachineState, Movie, Proc, ProgramState, Replay,
+    },
     error::Result,
+    gdb::GdbStub,
+    palette::Palette,
 };
 
+pub use crate::capture::Capture;
+use crate::controls::{state_message, Controls, Hotkey};
+pub use crate::debugger::Debugger;
+use crate::debugger::DebuggerAction;
+use crate::gamepad::Gamepads;
+pub use crate::gamepad::DEFAULT_PROFILE as DEFAULT_GAMEPAD_PROFILE;
 use crate::media::audio::AudioManager;
+pub use crate::media::filters::{Filter, Filters};
 use crate::media::screen;
+pub use crate::states::SaveSlots;
+pub use crate::terminal::{run_terminal, TerminalMode};
 
-const SCALE: i32 = 10;
-
-fn sdl_into_chipo(kc: SDLKeycode) -> Keycode {
-    match kc {
-        SDLKeycode::Num1 => Keycode::Num1,
-        SDLKeycode::Num2 => Keycode::Num2,
-        SDLKeycode::Num3 => Keycode::Num3,
-        SDLKeycode::Num4 => Keycode::Num4,
-        SDLKeycode::A => Keycode::A,
-        SDLKeycode::Z => Keycode::Z,
-        SDLKeycode::E => Keycode::E,
-        SDLKeycode::R => Keycode::R,
-        SDLKeycode::Q => Keycode::Q,
-        SDLKeycode::S => Keycode::S,
-        SDLKeycode::D => Keycode::D,
-        SDLKeycode::F => Keycode::F,
-        SDLKeycode::W => Keycode::W,
-        SDLKeycode::X => Keycode::X,
-        SDLKeycode::C => Keycode::C,
-        SDLKeycode::V => Keycode::V,
-        _ => Keycode::Other,
+// About 10 seconds of history, a frame is recorded on each timer tick
+const REWIND_FRAMES: usize = 600;
+
+// Name of an SDL key in keymaps: numpad keys are num0 to num9, num/, num*,
+// num-, num+, num. and numenter, the others their lowercase SDL name
+fn key_name(kc: SDLKeycode) -> String {
+    let name = kc.name().to_lowercase();
+    match name.strip_prefix("keypad ") {
+        Some("enter") => "numenter".to_string(),
+        Some(key) => format!("num{}", key),
+        None => name,
+    }
+}
+
+fn save_recording(capture: &mut Capture, frame: u64) {
+    match capture.stop_recording(frame) {
+        Ok(Some(path)) => println!("recording saved to {}", path.display()),
+        Ok(None) => println!("nothing was recorded"),
+        Err(err) => eprintln!("could not save recording: {}", err),
     }
 }
 
-fn proc_to_rects(proc: &Proc) -> Vec<Rect> {
-    proc.pixels
-        .iter()
-        .enumerate()
-        .filter_map(|(pos, &b)| {
-            if b {
-                Some(Rect::new(
-                    (pos % 64) as i32 * SCALE,
-                    (pos / 64) as i32 * SCALE,
-                    SCALE as u32,
-                    SCALE as u32,
-                ))
-            } else {
-                None
+// F1 to F10 control the emulation
+fn function_key(kc: SDLKeycode) -> Option<u8> {
+    let n = match kc {
+        SDLKeycode::F1 => 1,
+        SDLKeycode::F2 => 2,
+        SDLKeycode::F3 => 3,
+        SDLKeycode::F4 => 4,
+        SDLKeycode::F5 => 5,
+        SDLKeycode::F6 => 6,
+        SDLKeycode::F7 => 7,
+        SDLKeycode::F8 => 8,
+        SDLKeycode::F9 => 9,
+        SDLKeycode::F10 => 10,
+        _ => return None,
+    };
+    Some(n)
+}
+
+// Keys of the keyboard and the controllers pressing keypad keys
+struct WindowInput {
+    keymap: Keymap,
+    gamepads: Gamepads,
+    pending: Vec<(usize, bool)>,
+}
+
+impl WindowInput {
+    fn handle(&mut self, event: &Event) {
+        match *event {
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } => {
+                if let Some(key) = self.keymap.get(&key_name(keycode)) {
+                    self.pending.push((key, true));
+                }
+            }
+            Event::KeyUp {
+                keycode: Some(keycode),
+                ..
+            } => {
+                if let Some(key) = self.keymap.get(&key_name(keycode)) {
+                    self.pending.push((key, false));
+                }
             }
-        })
-        .collect::<Vec<Rect>>()
+            _ => self.pending.extend(self.gamepads.handle(event)),
+        }
+    }
+}
+
+impl Input for WindowInput {
+    fn poll(&mut self, _frame: u64) -> Vec<(usize, bool)> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// The screen in the window, with the filters and the recording of the frames
+struct Window<'a> {
+    canvas: WindowCanvas,
+    texture: Texture<'a>,
+    palette: Palette,
+    filters: Filters,
+    capture: Capture,
 }
 
-pub fn run(blob: &[u8]) -> Result<()> {
-    let (mut canvas, mut event_pump, mut audio) = screen::init();
+impl Display for Window<'_> {
+    fn draw(&mut self, proc: &Proc) {
+        let pixels = self.filters.pixels(proc, &self.palette);
+        self.texture
+            .update(None, &pixels, screen::WIDTH * 3)
+            .unwrap();
+        let viewport = screen::viewport(&self.canvas, proc.width(), proc.height());
+        // Black bars around the screen when the window has another aspect ratio
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        self.canvas.copy(&self.texture, None, viewport).unwrap();
+        self.filters.overlay(&mut self.canvas, viewport);
+        self.canvas.present();
+    }
+
+    fn end_frame(&mut self, proc: &Proc, frame: u64) {
+        self.capture.record(proc, frame);
+        self.filters.push(proc, &self.palette);
+    }
+
+    // The window keeps the size of the pixels
+    fn resize(&mut self, from: (usize, usize), to: (usize, usize)) {
+        screen::resize(&mut self.canvas, from, to);
+    }
+
+    // Fading pixels keep changing the image while the screen doesn't
+    fn is_animated(&self) -> bool {
+        self.filters.is_animated()
+    }
+}
+
+// Drives the processor with the clock and the movie of the options, keeping
+// a history of frames to rewind
+fn machine(proc: Proc, clock: Clock, movie: Option<Movie>, replay: Option<Replay>) -> Machine {
+    let mut machine = Machine::new(proc, clock).with_rewind(REWIND_FRAMES);
+    if let Some(movie) = movie {
+        machine.start_recording(movie);
+    }
+    if let Some(replay) = replay {
+        machine.start_replay(replay);
+    }
+    machine
+}
+
+pub struct Options {
+    // Size of a pixel at the low resolution in the window when it opens
+    pub scale: u32,
+    pub slots: SaveSlots,
+    pub capture: Capture,
+    pub palette: Palette,
+    pub filters: Filters,
+    pub debugger: Option<Debugger>,
+    pub gdb: Option<GdbStub>,
+    pub clock: Clock,
+    pub keymap: Keymap,
+    // Mapping of the controller buttons and sticks
+    pub gamepad: Keymap,
+    // Movie recording the key events, returned by run once finished
+    pub movie: Option<Movie>,
+    pub replay: Option<Replay>,
+}
+
+pub fn run(proc: Proc, options: Options) -> Result<Option<Movie>> {
+    let Options {
+        scale,
+        slots,
+        capture,
+        palette,
+        filters,
+        mut debugger,
+        mut gdb,
+        clock,
+        keymap,
+        gamepad,
+        movie,
+        replay,
+    } = options;
+    let mut machine = machine(proc, clock, movie, replay);
+    let (canvas, mut event_pump, mut audio, controllers) = screen::init(&palette, scale);
+    let texture_creator = canvas.texture_creator();
+    let texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            screen::WIDTH as u32,
+            screen::HEIGHT as u32,
+        )
+        .unwrap();
+    let mut window = Window {
+        canvas,
+        texture,
+        palette,
+        filters,
+        capture,
+    };
+    let mut input = WindowInput {
+        keymap,
+        gamepads: Gamepads::new(controllers, gamepad),
+        pending: vec![],
+    };
     let mut audio_manager = AudioManager::init(&mut audio);
-    let mut proc = Proc::binary(blob)?;
+    let mut controls = Controls::new(slots);
 
-    let mut last_update = Instant::now();
     'running: loop {
-        screen::clear(&mut canvas);
-
-        match proc.cycle() {
-            Ok(ProgramState::Continue) => {}
-            Ok(ProgramState::Stop) => break 'running,
-            Err(err) => {
-                return Err(err);
+        let mut prompted = false;
+        let state = controls.update(
+            &mut machine,
+            &mut window,
+            &mut audio_manager,
+            &mut input,
+            |proc, display, audio| {
+                if let Some(debugger) = debugger.as_mut() {
+                    if debugger.should_break(proc) {
+                        // Show the screen as it is before waiting on the terminal
+                        display.draw(proc);
+                        audio.set_sound(false, proc.audio_pattern(), proc.audio_rate());
+                        prompted = true;
+                        if let DebuggerAction::Quit = debugger.prompt(proc) {
+                            return Ok(ProgramState::Stop);
+                        }
+                    }
+                }
+                match gdb.as_mut() {
+                    Some(gdb) => gdb.cycle(proc),
+                    None => proc.cycle(),
+                }
+            },
+        )?;
+        if prompted {
+            controls.skip_time();
+        }
+        if let Some(message) = state_message(state) {
+            println!("{}", message);
+        }
+        match state {
+            MachineState::Stopped => break 'running,
+            // The debugger breaks right after the instruction
+            MachineState::Watchpoint(_) => {
+                if let Some(debugger) = debugger.as_mut() {
+                    debugger.pause();
+                }
             }
+            _ => {}
         }
 
-        audio_manager.set(proc.should_buzz());
-        let now = Instant::now();
-        let time_since_last = now.checked_duration_since(last_update);
-        if let Some(elapsed) = time_since_last {
-            if elapsed > Duration::from_millis(10) {
-                last_update = now;
-                proc.decrement_registers();
-                if proc.should_render {
-                    proc.should_render = false;
-                    canvas.fill_rects(&proc_to_rects(&proc)).unwrap();
-                    canvas.present();
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(SDLKeycode::Escape),
+                    ..
+                } => break 'running,
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                }
+                | Event::Window {
+                    win_event: WindowEvent::Exposed,
+                    ..
+                } => machine.proc_mut().should_render = true,
+                Event::KeyDown {
+                    keycode: Some(SDLKeycode::F11),
+                    ..
+                } => screen::toggle_fullscreen(&mut window.canvas),
+                Event::KeyDown {
+                    keycode: Some(SDLKeycode::F12),
+                    ..
+                } => {
+                    if let Some(debugger) = debugger.as_mut() {
+                        debugger.pause();
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(SDLKeycode::Backspace),
+                    ..
+                } => {
+                    controls.hotkey(Hotkey::Rewind(true), &mut machine, &mut window.capture);
+                }
+                Event::KeyUp {
+                    keycode: Some(SDLKeycode::Backspace),
+                    ..
+                } => {
+                    controls.hotkey(Hotkey::Rewind(false), &mut machine, &mut window.capture);
                 }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    ..
+                } if function_key(keycode).is_some() => {
+                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    let message = function_key(keycode)
+                        .and_then(|n| Hotkey::function_key(n, shift))
+                        .and_then(|hotkey| {
+                            controls.hotkey(hotkey, &mut machine, &mut window.capture)
+                        });
+                    if let Some(message) = message {
+                        println!("{}", message);
+                    }
+                }
+                event => input.handle(&event),
             }
         }
 
-        match event_pump.poll_event() {
-            Some(Event::Quit { .. })
-            | Some(Event::KeyDown {
-                keycode: Some(SDLKeycode::Escape),
-                ..
-            }) => break 'running,
-            Some(Event::KeyDown {
-                keycode: Some(keycode),
-                ..
-            }) => proc.set_key_down(sdl_into_chipo(keycode)),
-            Some(Event::KeyUp {
-                keycode: Some(keycode),
-                ..
-            }) => proc.set_key_up(sdl_into_chipo(keycode)),
-            _ => {}
-        }
+        // The clock catches up on the time slept
+        sleep(Duration::from_millis(1));
+    }
+
+    if window.capture.is_recording() {
+        save_recording(&mut window.capture, machine.clock().frame());
     }
-    Ok(())
+    Ok(machine.stop_recording())
 }
@@ -1,12 +1,29 @@
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use sdl2::AudioSubsystem;
 
+use chipo::emu::{Audio, Val};
+
 pub struct AudioManager {
     device: AudioDevice<SquareWave>,
     status: bool,
+    pattern: Option<[u8; 16]>,
+    rate: f32,
 }
 
 impl AudioManager {
+    // Only locks the audio device when the XO-CHIP pattern or pitch changed
+    pub fn set_pattern(&mut self, pattern: Option<&[u8; 16]>, rate: f32) {
+        let pattern = pattern.copied();
+        if pattern == self.pattern && (rate - self.rate).abs() < f32::EPSILON {
+            return;
+        }
+        self.pattern = pattern;
+        self.rate = rate;
+
+        let mut wave = self.device.lock();
+        wave.pattern = pattern;
+        wave.pattern_inc = rate / wave.freq;
+    }
     pub fn set(&mut self, value: bool) {
         if value && !self.status {
             self.device.resume();
@@ -24,28 +41,58 @@ impl AudioManager {
         };
         let device = subsys
             .open_playback(None, &desired_spec, |spec| SquareWave {
+                freq: spec.freq as f32,
                 phase_inc: 440.0 / spec.freq as f32,
                 phase: 0.0,
                 volume: 0.25,
+                pattern: None,
+                pattern_inc: 0.0,
+                pattern_phase: 0.0,
             })
             .unwrap();
         AudioManager {
             device,
             status: false,
+            pattern: None,
+            rate: 0.0,
         }
     }
 }
 
+impl Audio for AudioManager {
+    fn set_sound(&mut self, on: bool, pattern: Option<&[Val; 16]>, rate: f32) {
+        self.set_pattern(pattern, rate);
+        self.set(on);
+    }
+}
+
 pub struct SquareWave {
+    freq: f32,
     phase_inc: f32,
     phase: f32,
     volume: f32,
+    // XO-CHIP 1-bit audio pattern, played instead of the square wave
+    pattern: Option<[u8; 16]>,
+    pattern_inc: f32,
+    pattern_phase: f32,
 }
 
 impl AudioCallback for SquareWave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
+        if let Some(pattern) = self.pattern {
+            for x in out.iter_mut() {
+                let bit = self.pattern_phase as usize;
+                *x = if pattern[bit / 8] >> (7 - bit % 8) & 1 != 0 {
+                    self.volume
+                } else {
+                    -self.volume
+                };
+                self.pattern_phase = (self.pattern_phase + self.pattern_inc) % 128.0;
+            }
+            return;
+        }
         for x in out.iter_mut() {
             *x = if self.phase <= 0.5 {
                 self.volume
@@ -0,0 +1,163 @@
+use std::str::FromStr;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, WindowCanvas};
+
+use chipo::{
+    emu::Proc,
+    error::{ChipoError, Result},
+    palette::Palette,
+    screenshot::screen_image,
+};
+
+// Opacity of the dark lines drawn over the screen
+const SCANLINE_ALPHA: u8 = 80;
+const CRT_SCANLINE_ALPHA: u8 = 110;
+const CRT_MASK_ALPHA: u8 = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    // Pixels turned off fade out like the phosphor of a CRT
+    Ghosting,
+    // Each frame is mixed with the previous one
+    Blend,
+    // Every other line of the window is darkened
+    Scanlines,
+    // Stronger scanlines with a shadow mask over the columns
+    Crt,
+}
+
+impl FromStr for Filter {
+    type Err = ChipoError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "ghosting" => Ok(Filter::Ghosting),
+            "blend" => Ok(Filter::Blend),
+            "scanlines" => Ok(Filter::Scanlines),
+            "crt" => Ok(Filter::Crt),
+            _ => Err(ChipoError::UnknownFilter(name.to_string())),
+        }
+    }
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+    ]
+}
+
+fn rgb(color: [u8; 3]) -> [f32; 3] {
+    color.map(f32::from)
+}
+
+// Rendering filters reducing the flicker of XOR drawing, computed from the
+// frames shown so far. The emulation itself is left untouched.
+pub struct Filters {
+    ghosting: bool,
+    blend: bool,
+    scanlines: bool,
+    crt: bool,
+    // Fraction of its brightness a pixel loses each frame once turned off
+    decay: f32,
+    // Colors of the last frame as drawn by the program, then as shown
+    last: Vec<[f32; 3]>,
+    shown: Vec<[f32; 3]>,
+}
+
+impl Filters {
+    pub fn new(filters: &[Filter], decay: f64) -> Self {
+        Filters {
+            ghosting: filters.contains(&Filter::Ghosting),
+            blend: filters.contains(&Filter::Blend),
+            scanlines: filters.contains(&Filter::Scanlines),
+            crt: filters.contains(&Filter::Crt),
+            decay: decay.clamp(0.0, 1.0) as f32,
+            last: vec![],
+            shown: vec![],
+        }
+    }
+
+    // Whether the image changes from frame to frame with the same screen
+    pub fn is_animated(&self) -> bool {
+        self.ghosting || self.blend
+    }
+
+    // Adds the screen at the end of a frame to the history
+    pub fn push(&mut self, proc: &Proc, palette: &Palette) {
+        if !self.is_animated() {
+            return;
+        }
+        let (_, _, planes) = screen_image(proc, 1);
+        let colors = planes
+            .iter()
+            .map(|&planes| rgb(palette.color(planes)))
+            .collect::<Vec<[f32; 3]>>();
+        if self.last.is_empty() {
+            self.last = colors.clone();
+            self.shown = colors.clone();
+        }
+
+        for (i, (&planes, &color)) in planes.iter().zip(colors.iter()).enumerate() {
+            let target = if self.blend {
+                mix(color, self.last[i], 0.5)
+            } else {
+                color
+            };
+            // Lit pixels show up at once, the others fade to their new color
+            self.shown[i] = if self.ghosting && planes == 0 {
+                mix(self.shown[i], target, self.decay)
+            } else {
+                target
+            };
+        }
+        self.last = colors;
+    }
+
+    // RGB pixels to show, at the high resolution
+    pub fn pixels(&self, proc: &Proc, palette: &Palette) -> Vec<u8> {
+        if self.is_animated() && !self.shown.is_empty() {
+            self.shown
+                .iter()
+                .flat_map(|color| color.map(|c| c.round() as u8))
+                .collect()
+        } else {
+            let (_, _, planes) = screen_image(proc, 1);
+            planes
+                .iter()
+                .flat_map(|&planes| palette.color(planes))
+                .collect()
+        }
+    }
+
+    // Draws the scanlines and the shadow mask over the screen shown in rect
+    pub fn overlay(&self, canvas: &mut WindowCanvas, rect: Rect) {
+        if !self.scanlines && !self.crt {
+            return;
+        }
+        canvas.set_blend_mode(BlendMode::Blend);
+        let alpha = if self.crt {
+            CRT_SCANLINE_ALPHA
+        } else {
+            SCANLINE_ALPHA
+        };
+        let lines = (1..rect.height() as i32)
+            .step_by(2)
+            .map(|y| Rect::new(rect.x(), rect.y() + y, rect.width(), 1))
+            .collect::<Vec<Rect>>();
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, alpha));
+        canvas.fill_rects(&lines).unwrap();
+        if self.crt {
+            let columns = (2..rect.width() as i32)
+                .step_by(3)
+                .map(|x| Rect::new(rect.x() + x, rect.y(), 1, rect.height()))
+                .collect::<Vec<Rect>>();
+            canvas.set_draw_color(Color::RGBA(0, 0, 0, CRT_MASK_ALPHA));
+            canvas.fill_rects(&columns).unwrap();
+        }
+        canvas.set_blend_mode(BlendMode::None);
+    }
+}
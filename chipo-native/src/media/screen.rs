@@ -1,39 +1,97 @@
 extern crate sdl2;
 
 use sdl2::pixels::Color;
+use sdl2::rect::Rect;
 use sdl2::render::WindowCanvas;
-use sdl2::{AudioSubsystem, EventPump};
+use sdl2::video::FullscreenType;
+use sdl2::{AudioSubsystem, EventPump, GameControllerSubsystem};
 
-pub const SCALE: i32 = 10;
+use chipo::palette::Palette;
 
-pub fn init() -> (WindowCanvas, EventPump, AudioSubsystem) {
+pub use chipo::screenshot::{HEIGHT, WIDTH};
+
+// Opens a resizable window with pixels of scale window pixels at the low
+// resolution
+pub fn init(
+    palette: &Palette,
+    scale: u32,
+) -> (
+    WindowCanvas,
+    EventPump,
+    AudioSubsystem,
+    GameControllerSubsystem,
+) {
     let context = sdl2::init().unwrap();
     let video_subsystem = context.video().unwrap();
     let window = video_subsystem
-        .window("chip8 emulator", 64 * SCALE as u32, 32 * SCALE as u32)
+        .window("chip8 emulator", 64 * scale, 32 * scale)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
     let mut canvas = window.into_canvas().build().unwrap();
-    canvas.set_draw_color(background_color());
+    let [r, g, b] = palette.background();
+    canvas.set_draw_color(Color::RGB(r, g, b));
     canvas.clear();
-    canvas.set_draw_color(foreground_color());
     canvas.present();
     let event_pump = context.event_pump().unwrap();
     let audio = context.audio().unwrap();
-    (canvas, event_pump, audio)
+    let controllers = context.game_controller().unwrap();
+    (canvas, event_pump, audio, controllers)
 }
 
-pub fn clear(canvas: &mut WindowCanvas) {
-    canvas.set_draw_color(background_color());
-    canvas.clear();
-    canvas.set_draw_color(foreground_color());
+// Area of the window showing a screen of width x height pixels: the largest
+// integer multiple of its size that fits, centered, or the largest area with
+// the same aspect ratio when the window is smaller than the screen
+pub fn viewport(canvas: &WindowCanvas, width: usize, height: usize) -> Rect {
+    let (window_width, window_height) = canvas.output_size().unwrap();
+    let (width, height) = (width as u32, height as u32);
+    let scale = (window_width / width).min(window_height / height);
+    let (w, h) = if scale > 0 {
+        (width * scale, height * scale)
+    } else if window_width * height > window_height * width {
+        (window_height * width / height, window_height)
+    } else {
+        (window_width, window_width * height / width)
+    };
+    Rect::new(
+        ((window_width - w) / 2) as i32,
+        ((window_height - h) / 2) as i32,
+        w.max(1),
+        h.max(1),
+    )
 }
 
-fn foreground_color() -> Color {
-    Color::RGB(38, 84, 124)
+// Resizes the window when the program switches between resolutions so that
+// pixels keep their size, as long as the window fits on the display.
+// Fullscreen windows are left alone.
+pub fn resize(canvas: &mut WindowCanvas, from: (usize, usize), to: (usize, usize)) {
+    if canvas.window().fullscreen_state() != FullscreenType::Off {
+        return;
+    }
+    let (width, height) = (to.0 as u32, to.1 as u32);
+    let mut scale = (viewport(canvas, from.0, from.1).width() / from.0 as u32).max(1);
+    let window = canvas.window();
+    if let Ok(mode) = window
+        .display_index()
+        .and_then(|index| window.subsystem().desktop_display_mode(index))
+    {
+        while scale > 1 && (width * scale > mode.w as u32 || height * scale > mode.h as u32) {
+            scale -= 1;
+        }
+    }
+    if let Err(err) = canvas.window_mut().set_size(width * scale, height * scale) {
+        eprintln!("could not resize the window: {}", err);
+    }
 }
 
-fn background_color() -> Color {
-    Color::RGB(252, 252, 252)
+pub fn toggle_fullscreen(canvas: &mut WindowCanvas) {
+    let window = canvas.window_mut();
+    let state = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    if let Err(err) = window.set_fullscreen(state) {
+        eprintln!("could not toggle fullscreen: {}", err);
+    }
 }
@@ -0,0 +1,54 @@
+use std::fs::{read, write};
+use std::path::PathBuf;
+
+use chipo::{
+    emu::Proc,
+    error::{ChipoError, Result},
+};
+
+pub const SLOTS: usize = 4;
+
+// Save state slots, kept in memory and written to <rom>.state<n> when a rom path is known
+pub struct SaveSlots {
+    rom: Option<PathBuf>,
+    slots: Vec<Option<Vec<u8>>>,
+}
+
+impl SaveSlots {
+    pub fn new(rom: Option<PathBuf>) -> Self {
+        SaveSlots {
+            rom,
+            slots: vec![None; SLOTS],
+        }
+    }
+
+    fn path(&self, slot: usize) -> Option<PathBuf> {
+        self.rom
+            .as_ref()
+            .map(|rom| rom.with_extension(format!("state{}", slot + 1)))
+    }
+
+    pub fn save(&mut self, slot: usize, proc: &Proc) -> Result<()> {
+        let state = proc.save_state();
+        if let Some(path) = self.path(slot) {
+            write(path, &state)?;
+        }
+        self.slots[slot] = Some(state);
+        Ok(())
+    }
+
+    pub fn load(&mut self, slot: usize, proc: &mut Proc) -> Result<()> {
+        if self.slots[slot].is_none() {
+            if let Some(path) = self.path(slot).filter(|path| path.exists()) {
+                self.slots[slot] = Some(read(path)?);
+            }
+        }
+        match &self.slots[slot] {
+            Some(state) => proc.load_state(state),
+            None => Err(ChipoError::InvalidState(format!(
+                "slot {} is empty",
+                slot + 1
+            ))),
+        }
+    }
+}
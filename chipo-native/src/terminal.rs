@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::io::{stdout, Stdout, Write};
+use std::thread::sleep;
+use std::time::Duration;
+
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, queue,
+    style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal::{
+        self, disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
+        LeaveAlternateScreen,
+    },
+};
+
+use chipo::{
+    emu::{Audio, Display, Input, Keymap, MachineState, Movie, Proc, Val},
+    error::Result,
+    palette::Palette,
+};
+
+use crate::controls::{state_message, Controls, Hotkey};
+use crate::{machine, save_recording, Capture, Options};
+
+// Most terminals only report key presses, repeated while the key is held.
+// Keys are then released after a while without a repeat, long enough for the
+// first repeat to come.
+const FIRST_HOLD_FRAMES: u64 = 30;
+const REPEAT_HOLD_FRAMES: u64 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalMode {
+    // Two pixels per character with the upper half block, in color
+    HalfBlocks,
+    // Eight pixels per character with braille patterns, in two colors
+    Braille,
+}
+
+// Puts the terminal back as it was, even when leaving on an error
+struct RawTerminal {
+    out: Stdout,
+    enhanced: bool,
+}
+
+impl RawTerminal {
+    fn new() -> Result<Self> {
+        let mut out = stdout();
+        enable_raw_mode()?;
+        execute!(out, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        // Terminals with the kitty keyboard protocol report key releases
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(
+                out,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(RawTerminal { out, enhanced })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if self.enhanced {
+            let _ = execute!(self.out, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.out, ResetColor, Show, LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+// Name of a terminal key in keymaps, the same as the SDL frontend for the
+// keys terminals can tell apart. The numpad sends digits.
+fn key_name(code: KeyCode) -> Option<String> {
+    let name = match code {
+        KeyCode::Char(' ') => "space".to_string(),
+        KeyCode::Char(c) => c.to_lowercase().to_string(),
+        KeyCode::Up => "up".to_string(),
+        KeyCode::Down => "down".to_string(),
+        KeyCode::Left => "left".to_string(),
+        KeyCode::Right => "right".to_string(),
+        KeyCode::Enter => "return".to_string(),
+        KeyCode::Tab => "tab".to_string(),
+        _ => return None,
+    };
+    Some(name)
+}
+
+fn color(palette: &Palette, planes: u8) -> Color {
+    let [r, g, b] = palette.color(planes);
+    Color::Rgb { r, g, b }
+}
+
+// Draws the screen from the top left corner of the terminal
+fn draw(out: &mut Stdout, proc: &Proc, palette: &Palette, mode: TerminalMode) -> Result<()> {
+    let (width, height) = (proc.width(), proc.height());
+    let pixel = |x: usize, y: usize| {
+        if x < width && y < height {
+            proc.pixels[y * width + x] & 0x3
+        } else {
+            0
+        }
+    };
+    let (cell_width, cell_height) = match mode {
+        TerminalMode::HalfBlocks => (1, 2),
+        TerminalMode::Braille => (2, 4),
+    };
+
+    let mut colors = None;
+    for row in 0..height.div_ceil(cell_height) {
+        queue!(out, MoveTo(0, row as u16))?;
+        for column in 0..width.div_ceil(cell_width) {
+            let (x, y) = (column * cell_width, row * cell_height);
+            let (cell, fg, bg) = match mode {
+                TerminalMode::HalfBlocks => ('▀', pixel(x, y), pixel(x, y + 1)),
+                TerminalMode::Braille => {
+                    // Dots are numbered down the left column then the right
+                    // one, the bottom row comes last
+                    const DOTS: [(usize, usize, u32); 8] = [
+                        (0, 0, 0x01),
+                        (0, 1, 0x02),
+                        (0, 2, 0x04),
+                        (1, 0, 0x08),
+                        (1, 1, 0x10),
+                        (1, 2, 0x20),
+                        (0, 3, 0x40),
+                        (1, 3, 0x80),
+                    ];
+                    let lit = DOTS
+                        .iter()
+                        .filter(|(dx, dy, _)| pixel(x + dx, y + dy) != 0)
+                        .collect::<Vec<_>>();
+                    let bits = lit.iter().map(|(_, _, bit)| bit).sum::<u32>();
+                    let planes = lit.first().map_or(1, |(dx, dy, _)| pixel(x + dx, y + dy));
+                    let cell = std::char::from_u32(0x2800 + bits).unwrap_or(' ');
+                    (cell, planes, 0)
+                }
+            };
+            // Only send the colors when they change
+            if colors != Some((fg, bg)) {
+                queue!(
+                    out,
+                    SetForegroundColor(color(palette, fg)),
+                    SetBackgroundColor(color(palette, bg))
+                )?;
+                colors = Some((fg, bg));
+            }
+            queue!(out, Print(cell))?;
+        }
+    }
+    queue!(out, ResetColor)?;
+    Ok(())
+}
+
+fn draw_status(out: &mut Stdout, row: u16, status: &str) -> Result<()> {
+    queue!(
+        out,
+        MoveTo(0, row),
+        Clear(ClearType::CurrentLine),
+        Print(status)
+    )?;
+    Ok(())
+}
+
+// The screen drawn in the terminal, with a status line below it
+struct Screen {
+    terminal: RawTerminal,
+    palette: Palette,
+    mode: TerminalMode,
+    capture: Capture,
+    status: String,
+    // Whether the whole terminal must be cleared before drawing
+    redraw: bool,
+}
+
+impl Screen {
+    fn status_row(&self, proc: &Proc) -> u16 {
+        match self.mode {
+            TerminalMode::HalfBlocks => proc.height().div_ceil(2) as u16,
+            TerminalMode::Braille => proc.height().div_ceil(4) as u16,
+        }
+    }
+
+    fn try_draw(&mut self, proc: &Proc) -> Result<()> {
+        let row = self.status_row(proc);
+        let out = &mut self.terminal.out;
+        if self.redraw {
+            queue!(out, Clear(ClearType::All))?;
+            self.redraw = false;
+        }
+        draw(out, proc, &self.palette, self.mode)?;
+        draw_status(out, row, &self.status)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Display for Screen {
+    // A terminal failing to write is closed anyway, the emulation goes on
+    fn draw(&mut self, proc: &Proc) {
+        let _ = self.try_draw(proc);
+    }
+
+    fn end_frame(&mut self, proc: &Proc, frame: u64) {
+        self.capture.record(proc, frame);
+    }
+
+    // The status line moves with the bottom of the screen
+    fn resize(&mut self, _from: (usize, usize), _to: (usize, usize)) {
+        self.redraw = true;
+    }
+}
+
+// Keys of the terminal pressing keypad keys, held until they stop repeating
+// when the terminal doesn't report releases
+struct TerminalInput {
+    keymap: Keymap,
+    enhanced: bool,
+    // Frame at which the keys held down are released
+    held: HashMap<usize, u64>,
+    pending: Vec<(usize, bool)>,
+}
+
+impl TerminalInput {
+    fn handle(&mut self, code: KeyCode, pressed: bool, frame: u64) {
+        let key = match key_name(code).and_then(|name| self.keymap.get(&name)) {
+            Some(key) => key,
+            None => return,
+        };
+        if !pressed {
+            self.held.remove(&key);
+            self.pending.push((key, false));
+        } else if self.enhanced {
+            self.held.insert(key, u64::MAX);
+            self.pending.push((key, true));
+        } else if let Some(until) = self.held.get_mut(&key) {
+            *until = frame + REPEAT_HOLD_FRAMES;
+        } else {
+            self.held.insert(key, frame + FIRST_HOLD_FRAMES);
+            self.pending.push((key, true));
+        }
+    }
+}
+
+impl Input for TerminalInput {
+    fn poll(&mut self, frame: u64) -> Vec<(usize, bool)> {
+        let released = self
+            .held
+            .iter()
+            .filter(|(_, &until)| until <= frame)
+            .map(|(&key, _)| key)
+            .collect::<Vec<usize>>();
+        for key in released {
+            self.held.remove(&key);
+            self.pending.push((key, false));
+        }
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// The terminal bell rings when the sound timer starts
+#[derive(Default)]
+struct Bell {
+    buzzing: bool,
+}
+
+impl Audio for Bell {
+    fn set_sound(&mut self, on: bool, _pattern: Option<&[Val; 16]>, _rate: f32) {
+        if on && !self.buzzing {
+            let _ = execute!(stdout(), Print('\x07'));
+        }
+        self.buzzing = on;
+    }
+}
+
+// Runs the program in the terminal, with the same keymap, clock and movies
+// as the window. Esc or Ctrl+C quits and F1 to F10 work as in the window.
+pub fn run_terminal(proc: Proc, options: Options, mode: TerminalMode) -> Result<Option<Movie>> {
+    let Options {
+        slots,
+        capture,
+        palette,
+        mut gdb,
+        clock,
+        keymap,
+        movie,
+        replay,
+        ..
+    } = options;
+    let mut machine = machine(proc, clock, movie, replay);
+    let terminal = RawTerminal::new()?;
+    let mut input = TerminalInput {
+        keymap,
+        enhanced: terminal.enhanced,
+        held: HashMap::new(),
+        pending: vec![],
+    };
+    let mut screen = Screen {
+        terminal,
+        palette,
+        mode,
+        capture,
+        status: String::from("Esc quits"),
+        redraw: true,
+    };
+    let mut bell = Bell::default();
+    let mut controls = Controls::new(slots);
+    machine.proc_mut().should_render = true;
+
+    'running: loop {
+        let state = controls.update(
+            &mut machine,
+            &mut screen,
+            &mut bell,
+            &mut input,
+            |proc, _, _| match gdb.as_mut() {
+                Some(gdb) => gdb.cycle(proc),
+                None => proc.cycle(),
+            },
+        )?;
+        if let MachineState::Stopped = state {
+            break 'running;
+        }
+        if let MachineState::Watchpoint(_) = state {
+            machine.clock_mut().set_paused(true);
+        }
+        if let Some(message) = state_message(state) {
+            screen.status = message;
+            screen.redraw = true;
+        }
+
+        while event::poll(Duration::ZERO)? {
+            let (code, modifiers, kind) = match event::read()? {
+                Event::Key(KeyEvent {
+                    code,
+                    modifiers,
+                    kind,
+                    ..
+                }) => (code, modifiers, kind),
+                Event::Resize(..) => {
+                    screen.redraw = true;
+                    continue;
+                }
+                _ => continue,
+            };
+            let pressed = kind != KeyEventKind::Release;
+            match code {
+                KeyCode::Esc => break 'running,
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => break 'running,
+                KeyCode::F(n) => {
+                    let shift = modifiers.contains(KeyModifiers::SHIFT);
+                    let message =
+                        Hotkey::function_key(n, shift)
+                            .filter(|_| pressed)
+                            .and_then(|hotkey| {
+                                controls.hotkey(hotkey, &mut machine, &mut screen.capture)
+                            });
+                    if let Some(message) = message {
+                        screen.status = message;
+                        screen.redraw = true;
+                    }
+                }
+                code => input.handle(code, pressed, machine.clock().frame()),
+            }
+        }
+        if screen.redraw {
+            machine.proc_mut().should_render = true;
+            machine.present(&mut screen, &mut bell);
+        }
+
+        sleep(Duration::from_millis(1));
+    }
+
+    let frame = machine.clock().frame();
+    let mut capture = screen.capture;
+    drop(screen.terminal);
+    if capture.is_recording() {
+        save_recording(&mut capture, frame);
+    }
+    Ok(machine.stop_recording())
+}
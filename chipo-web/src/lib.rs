@@ -1,82 +1,263 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
 use wasm_bindgen::prelude::*;
 
-use chipo::emu::{Keycode, Proc};
+use chipo::emu::{
+    Clock, Keymap, Machine, MachineState, Movie, Proc, ProgramState, Quirks, Watchpoint,
+};
 use chipo::error::ChipoError;
-use chipo::{compile as chipo_compile, reverse_parse as chipo_reverse_parse};
+use chipo::palette::Palette;
+use chipo::{compile_with_files, reverse_parse as chipo_reverse_parse};
 
 // An Emulator is a wrapper for a Proc
 // It can be accessed the functions _emulator(emu: &Emulator)
 // instead of methods to bridge to JavaScript.
 #[wasm_bindgen]
 pub struct Emulator {
-    proc: Proc,
+    machine: Machine,
+    rom: Vec<u8>,
+    keymap: Keymap,
 }
 
+// About 10 seconds of history at 60 frames per second
+const REWIND_FRAMES: usize = 600;
+
 #[wasm_bindgen]
 pub fn cycle_emulator(emu: &mut Emulator) -> JsValue {
-    match emu.proc.cycle() {
-        Ok(chipo::emu::ProgramState::Stop) => JsValue::from("stop"),
-        Ok(chipo::emu::ProgramState::Continue) => JsValue::from(0),
+    state_to_js(emu.machine.proc_mut().cycle())
+}
+
+// Runs the frames due after elapsed_ms milliseconds, each frame is recorded
+// in the rewind history. The screen and the sound are read from JavaScript.
+#[wasm_bindgen]
+pub fn run_emulator(emu: &mut Emulator, elapsed_ms: f64) -> JsValue {
+    let elapsed = Duration::from_secs_f64(elapsed_ms.max(0.0) / 1000.0);
+    match emu.machine.update(elapsed, &mut (), &mut (), &mut ()) {
+        Ok(MachineState::Running) => JsValue::from(0),
+        Ok(MachineState::Stopped) => JsValue::from("stop"),
+        Ok(MachineState::Watchpoint(watchpoint)) => JsValue::from(format!("watch {}", watchpoint)),
+        Ok(MachineState::ReplayFinished(_)) => JsValue::from("replay finished"),
         Err(err) => JsValue::from(err.to_string()),
     }
 }
 
+#[wasm_bindgen]
+pub fn set_instructions_per_second_emulator(emu: &mut Emulator, instructions_per_second: u32) {
+    emu.machine
+        .clock_mut()
+        .set_instructions_per_second(instructions_per_second as u64);
+}
+
+fn state_to_js(state: chipo::error::Result<ProgramState>) -> JsValue {
+    match state {
+        Ok(ProgramState::Stop) => JsValue::from("stop"),
+        Ok(ProgramState::Continue) => JsValue::from(0),
+        Ok(ProgramState::Watchpoint(watchpoint)) => JsValue::from(format!("watch {}", watchpoint)),
+        Err(err) => JsValue::from(err.to_string()),
+    }
+}
+
+#[wasm_bindgen]
+pub fn watch_memory_emulator(emu: &mut Emulator, addr: usize, len: usize) {
+    emu.machine
+        .proc_mut()
+        .add_watchpoint(Watchpoint::memory(addr, len));
+}
+
+#[wasm_bindgen]
+pub fn watch_register_emulator(emu: &mut Emulator, vx: usize, value: u8) {
+    emu.machine
+        .proc_mut()
+        .add_watchpoint(Watchpoint::Register(vx & 0xF, value));
+}
+
+#[wasm_bindgen]
+pub fn watch_index_emulator(emu: &mut Emulator, value: u32) {
+    emu.machine
+        .proc_mut()
+        .add_watchpoint(Watchpoint::Index(value));
+}
+
+#[wasm_bindgen]
+pub fn clear_watchpoints_emulator(emu: &mut Emulator) {
+    emu.machine.proc_mut().clear_watchpoints();
+}
+
 #[wasm_bindgen]
 pub fn get_display_buffer_emulator(emu: &Emulator, pixels: &mut [u8]) {
-    for (i, p) in emu.proc.pixels.iter().enumerate() {
-        pixels[i] = *p as u8;
+    let proc = emu.machine.proc();
+    let size = proc.width() * proc.height();
+    for (i, p) in proc.pixels[..size].iter().enumerate() {
+        pixels[i] = *p;
     }
 }
 
+#[wasm_bindgen]
+pub fn get_display_width_emulator(emu: &Emulator) -> usize {
+    emu.machine.proc().width()
+}
+
+#[wasm_bindgen]
+pub fn get_display_height_emulator(emu: &Emulator) -> usize {
+    emu.machine.proc().height()
+}
+
 #[wasm_bindgen]
 pub fn decrement_registers_emulator(emu: &mut Emulator) {
-    emu.proc.decrement_registers();
+    emu.machine.proc_mut().decrement_registers();
 }
 
 #[wasm_bindgen]
 pub fn should_buzz(emu: &Emulator) -> bool {
-    emu.proc.should_buzz()
-}
-
-fn match_keycode(val: &str) -> Keycode {
-    match val {
-        "digit1" => Keycode::Num1,
-        "digit2" => Keycode::Num2,
-        "digit3" => Keycode::Num3,
-        "digit4" => Keycode::Num4,
-        "keya" => Keycode::A,
-        "keyq" => Keycode::Q,
-        "keyz" => Keycode::Z,
-        "keye" => Keycode::E,
-        "keyr" => Keycode::R,
-        "keys" => Keycode::S,
-        "keyd" => Keycode::D,
-        "keyf" => Keycode::F,
-        "keyw" => Keycode::W,
-        "keyx" => Keycode::X,
-        "keyc" => Keycode::C,
-        "keyv" => Keycode::V,
-        _ => Keycode::Other,
+    emu.machine.proc().should_buzz()
+}
+
+// Copies the XO-CHIP audio pattern, returns false when there is none
+#[wasm_bindgen]
+pub fn get_audio_pattern_emulator(emu: &Emulator, pattern: &mut [u8]) -> bool {
+    if let Some(buffer) = emu.machine.proc().audio_pattern() {
+        pattern[..16].copy_from_slice(buffer);
+        true
+    } else {
+        false
+    }
+}
+
+#[wasm_bindgen]
+pub fn get_audio_rate_emulator(emu: &Emulator) -> f32 {
+    emu.machine.proc().audio_rate()
+}
+
+// Name in keymaps of a lowercase KeyboardEvent.code, which names keys after
+// their position on a QWERTY keyboard whatever the layout
+fn key_name(code: &str) -> String {
+    let name = code
+        .strip_prefix("key")
+        .or_else(|| code.strip_prefix("digit"))
+        .or_else(|| code.strip_prefix("arrow"))
+        .unwrap_or(code);
+    match name.strip_prefix("numpad") {
+        Some("divide") => "num/".to_string(),
+        Some("multiply") => "num*".to_string(),
+        Some("subtract") => "num-".to_string(),
+        Some("add") => "num+".to_string(),
+        Some("decimal") => "num.".to_string(),
+        Some(key) => format!("num{}", key),
+        None => name.to_string(),
+    }
+}
+
+// The keyboard is ignored while a movie is replayed
+fn set_key(emu: &mut Emulator, key: &str, down: bool) {
+    if let Some(key) = emu.keymap.get(&key_name(key)) {
+        emu.machine.set_key(key, down);
     }
 }
 
+// Maps the keyboard with a preset then a keymap configuration, whose [<rom>]
+// sections match the CRC-32 of the program
+#[wasm_bindgen]
+pub fn set_keymap_emulator(emu: &mut Emulator, preset: &str, config: &str) -> Result<(), JsValue> {
+    let mut keymap = preset.parse::<Keymap>().map_err(convert_err)?;
+    keymap
+        .configure(config, None, &emu.rom)
+        .map_err(convert_err)?;
+    emu.keymap = keymap;
+    Ok(())
+}
+
+// Colors of a palette theme with a palette configuration applied, as four
+// CSS colors separated by commas
+#[wasm_bindgen]
+pub fn get_palette(theme: &str, config: &str) -> Result<String, JsValue> {
+    let mut palette = theme.parse::<Palette>().map_err(convert_err)?;
+    palette.configure(config).map_err(convert_err)?;
+    Ok(palette.to_string())
+}
+
 #[wasm_bindgen]
 pub fn set_key_up_emulator(emu: &mut Emulator, key: &str) {
-    let keycode = match_keycode(key);
-    emu.proc.set_key_up(keycode);
+    set_key(emu, key, false);
 }
 
 #[wasm_bindgen]
 pub fn set_key_down_emulator(emu: &mut Emulator, key: &str) {
-    let keycode = match_keycode(key);
-    emu.proc.set_key_down(keycode);
+    set_key(emu, key, true);
 }
 
-#[wasm_bindgen]
-pub fn new_emulator(code: &[u8]) -> Emulator {
+fn emulator(rom: &[u8], proc: Proc, clock: Clock) -> Emulator {
     Emulator {
-        proc: Proc::binary(code).unwrap(),
+        machine: Machine::new(proc, clock).with_rewind(REWIND_FRAMES),
+        rom: rom.to_vec(),
+        keymap: Keymap::default(),
+    }
+}
+
+#[wasm_bindgen]
+pub fn new_emulator(code: &[u8], quirks: &str) -> Result<Emulator, JsValue> {
+    let quirks = quirks.parse::<Quirks>().map_err(convert_err)?;
+    let proc = Proc::with_quirks(code, quirks).map_err(convert_err)?;
+    Ok(emulator(code, proc, Clock::default()))
+}
+
+// Creates an emulator replaying the key events of a movie
+#[wasm_bindgen]
+pub fn new_emulator_from_movie(code: &[u8], movie: &str) -> Result<Emulator, JsValue> {
+    let movie = movie.parse::<Movie>().map_err(convert_err)?;
+    let (proc, clock) = movie.start(code).map_err(convert_err)?;
+    let mut emu = emulator(code, proc, clock);
+    emu.machine.start_replay(movie.replay());
+    Ok(emu)
+}
+
+// Restarts the program and records the key events from there
+#[wasm_bindgen]
+pub fn start_recording_emulator(emu: &mut Emulator) -> Result<(), JsValue> {
+    let previous = emu.machine.proc();
+    let mut proc = Proc::with_quirks(&emu.rom, previous.quirks()).map_err(convert_err)?;
+    proc.set_seed(previous.seed());
+    let clock = Clock::new(emu.machine.clock().instructions_per_second());
+    let movie = Movie::new(&emu.rom, &proc, &clock);
+    emu.machine = Machine::new(proc, clock).with_rewind(REWIND_FRAMES);
+    emu.machine.start_recording(movie);
+    Ok(())
+}
+
+// Stops recording and returns the movie as text, if one was recorded
+#[wasm_bindgen]
+pub fn stop_recording_emulator(emu: &mut Emulator) -> Option<String> {
+    emu.machine.stop_recording().map(|movie| movie.to_string())
+}
+
+#[wasm_bindgen]
+pub fn save_state_emulator(emu: &Emulator) -> Vec<u8> {
+    emu.machine.proc().save_state()
+}
+
+#[wasm_bindgen]
+pub fn load_state_emulator(emu: &mut Emulator, state: &[u8]) -> Result<(), JsValue> {
+    if emu.machine.has_movie() {
+        return Err(JsValue::from_str("states can't be loaded with a movie"));
     }
+    emu.machine
+        .proc_mut()
+        .load_state(state)
+        .map_err(convert_err)?;
+    emu.machine.clear_rewind();
+    Ok(())
+}
+
+// While rewinding, the frames due step back in the history instead of running
+#[wasm_bindgen]
+pub fn set_rewinding_emulator(emu: &mut Emulator, rewinding: bool) {
+    emu.machine.set_rewinding(rewinding);
+}
+
+// Steps back up to frames frames, returns how many were actually rewound
+#[wasm_bindgen]
+pub fn rewind_emulator(emu: &mut Emulator, frames: usize) -> Result<usize, JsValue> {
+    emu.machine.rewind(frames).map_err(convert_err)
 }
 
 #[wasm_bindgen]
@@ -88,9 +269,27 @@ fn convert_err(err: ChipoError) -> JsValue {
     JsValue::from_str(&err.to_string())
 }
 
+// Files the program can include, there is no file system in the browser
+#[wasm_bindgen]
+pub struct Files {
+    files: HashMap<String, Vec<u8>>,
+}
+
+#[wasm_bindgen]
+pub fn new_files() -> Files {
+    Files {
+        files: HashMap::new(),
+    }
+}
+
+#[wasm_bindgen]
+pub fn add_file(files: &mut Files, name: &str, data: &[u8]) {
+    files.files.insert(name.to_string(), data.to_vec());
+}
+
 #[wasm_bindgen]
-pub fn compile(code: &str, slice: &mut [u8]) -> Result<usize, JsValue> {
-    let code = chipo_compile(code).map_err(convert_err)?;
+pub fn compile(code: &str, files: &Files, slice: &mut [u8]) -> Result<usize, JsValue> {
+    let code = compile_with_files(code, &files.files).map_err(convert_err)?;
     for (i, a) in code.iter().enumerate() {
         slice[i] = *a;
     }
@@ -1,28 +1,63 @@
 import init, {
   compile,
+  new_files,
+  add_file,
   new_emulator,
+  new_emulator_from_movie,
+  start_recording_emulator,
+  stop_recording_emulator,
+  set_keymap_emulator,
+  get_palette,
   cycle_emulator,
+  run_emulator,
   get_display_buffer_emulator,
+  get_display_width_emulator,
+  get_display_height_emulator,
   decrement_registers_emulator,
 	should_buzz,
+	get_audio_pattern_emulator,
+	get_audio_rate_emulator,
 	set_key_down_emulator,
 	set_key_up_emulator,
 	reverse_parse,
+	save_state_emulator,
+	load_state_emulator,
+	set_rewinding_emulator,
+	rewind_emulator,
 } from "../pkg/chipo_web.js";
 
 function clearScreen() {
-  ctx.fillStyle = "rgb(50, 50, 50)";
+  ctx.fillStyle = planeColors[0];
   ctx.fillRect(0, 0, 64 * SCALE, 32 * SCALE);
 }
 
-const MAX_SIZE = 2048;
+const MAX_SIZE = 0x10000 - 0x200;
 const SCALE = 5;
-const N_PIXELS = 64 * 32;
+const N_PIXELS = 128 * 64;
 let audioCtx = new (window.AudioContext || window.webkitAudioContext)();
-function Emulator(code) {
+function Emulator(code, movie) {
 	// this.emu is a reference to Rust Emulator struct. 
-  this.emu = new_emulator(code);
+  this.emu = movie
+    ? new_emulator_from_movie(code, movie)
+    : new_emulator(code, QUIRKS[quirksIndex]);
 	this._newOscillator = () => {
+		const pattern = new Uint8Array(16);
+		if (get_audio_pattern_emulator(this.emu, pattern)) {
+			// Loop over the 128 bits of the XO-CHIP audio pattern
+			const rate = get_audio_rate_emulator(this.emu);
+			const length = Math.max(1, Math.round((128 * audioCtx.sampleRate) / rate));
+			const buffer = audioCtx.createBuffer(1, length, audioCtx.sampleRate);
+			const data = buffer.getChannelData(0);
+			for (let i = 0; i < length; i++) {
+				const bit = Math.floor((i * 128) / length);
+				data[i] = (pattern[bit >> 3] >> (7 - (bit & 7))) & 1 ? 0.25 : -0.25;
+			}
+			const source = audioCtx.createBufferSource();
+			source.buffer = buffer;
+			source.loop = true;
+			source.connect(audioCtx.destination);
+			return source;
+		}
 		const oscillator = audioCtx.createOscillator();
 		oscillator.type = "square";
 		oscillator.frequency.value = 400;
@@ -34,8 +69,19 @@ function Emulator(code) {
   this._isPlaying = true;
 
   this.cycle = function () {
-    const res = cycle_emulator(this.emu);
+    this._handleResult(cycle_emulator(this.emu));
+  };
+
+  // Runs what is due after elapsed milliseconds, timers included
+  this.run = function (elapsed) {
+    this._handleResult(run_emulator(this.emu, elapsed));
+  };
+
+  this._handleResult = function (res) {
     if (res !== 0) {
+      if (typeof res === "string" && (res.startsWith("watch") || res.startsWith("replay"))) {
+        console.info(`stopped on ${res}`);
+      }
       this._isPlaying = false;
     }
   };
@@ -43,12 +89,13 @@ function Emulator(code) {
   this.isPlaying = () => this._isPlaying;
 	this.doBuzz = () => {
 		if (!this._audioRunning && should_buzz(this.emu)) {
+			// Recreated on each start to pick up the latest audio pattern
+			this._oscillator = this._newOscillator();
 			this._oscillator.start();
 			this._audioRunning = true;
 		} else if (this._audioRunning && !should_buzz(this.emu)) {
 			this._oscillator.stop();
 			this._audioRunning = false;
-			this._oscillator = this._newOscillator();
 		}
 	}
 
@@ -60,6 +107,14 @@ function Emulator(code) {
 		set_key_down_emulator(this.emu, key);
 	}
 
+	this.saveState = () => save_state_emulator(this.emu);
+	this.loadState = (state) => load_state_emulator(this.emu, state);
+	this.setRewinding = (rewinding) => set_rewinding_emulator(this.emu, rewinding);
+	this.rewind = (frames) => rewind_emulator(this.emu, frames);
+	this.setKeymap = (preset, config) => set_keymap_emulator(this.emu, preset, config);
+	this.startRecording = () => start_recording_emulator(this.emu);
+	this.stopRecording = () => stop_recording_emulator(this.emu);
+
   this.decrementRegisters = function () {
     decrement_registers_emulator(this.emu);
   };
@@ -72,15 +127,20 @@ function Emulator(code) {
   this.display = function () {
     const pixels = new Uint8Array(N_PIXELS);
     get_display_buffer_emulator(this.emu, pixels);
+    const width = get_display_width_emulator(this.emu);
+    const height = get_display_height_emulator(this.emu);
+    // The canvas is sized for 64x32, pixels shrink in high resolution mode
+    const size = (64 * SCALE) / width;
 
-    ctx.fillStyle = "rgb(50, 50, 50)";
+    ctx.fillStyle = planeColors[0];
     ctx.fillRect(0, 0, 64 * SCALE, 32 * SCALE);
 
-    ctx.fillStyle = "rgb(0, 255, 100)";
-    for (let x = 0; x < 64; x++) {
-      for (let y = 0; y < 32; y++) {
-        if (pixels[y * 64 + x] !== 0) {
-          ctx.fillRect(x * SCALE, y * SCALE, SCALE, SCALE);
+    for (let x = 0; x < width; x++) {
+      for (let y = 0; y < height; y++) {
+        const planes = pixels[y * width + x];
+        if (planes !== 0) {
+          ctx.fillStyle = planeColors[planes];
+          ctx.fillRect(x * size, y * size, size, size);
         }
       }
     }
@@ -101,6 +161,130 @@ initEvent("toggle-auto-save", (span) => {
   span.innerText = autoSave ? "[x]" : "[  ]";
 });
 
+const QUIRKS = ["default", "vip", "chip-48", "schip", "xo-chip"];
+let quirksIndex = 0;
+initEvent("cycle-quirks", (span) => {
+  quirksIndex = (quirksIndex + 1) % QUIRKS.length;
+  span.innerText = `[${QUIRKS[quirksIndex]}]`;
+});
+
+// Save states are kept in localStorage as base64 strings
+const STATE_SLOTS = 4;
+let stateSlot = 1;
+initEvent("cycle-state-slot", (span) => {
+  stateSlot = (stateSlot % STATE_SLOTS) + 1;
+  span.innerText = `[${stateSlot}]`;
+});
+initEvent("save-state", () => {
+  if (!emu) return;
+  const state = emu.saveState();
+  let binary = "";
+  for (let i = 0; i < state.length; i++) binary += String.fromCharCode(state[i]);
+  localStorage.setItem(`state-slot-${stateSlot}`, btoa(binary));
+});
+initEvent("load-state", () => {
+  const saved = localStorage.getItem(`state-slot-${stateSlot}`);
+  if (!emu || !saved) return;
+  const binary = atob(saved);
+  const state = new Uint8Array(binary.length);
+  for (let i = 0; i < binary.length; i++) state[i] = binary.charCodeAt(i);
+  try {
+    emu.loadState(state);
+  } catch (err) {
+    showError(err);
+  }
+});
+
+// The keymap preset and configuration are kept in localStorage, the
+// configuration can override keys per ROM in [<crc32>] sections
+const KEYMAPS = ["qwerty", "azerty", "numpad"];
+let keymapIndex = Math.max(0, KEYMAPS.indexOf(localStorage.getItem("keymap")));
+const keymapConfig = document.getElementById("keymap-config");
+keymapConfig.value = localStorage.getItem("keymap-config") || "";
+const applyKeymap = () => {
+  if (!emu) return;
+  try {
+    emu.setKeymap(KEYMAPS[keymapIndex], keymapConfig.value);
+    hideError();
+  } catch (err) {
+    showError(err);
+  }
+};
+initEvent("cycle-keymap", (span) => {
+  keymapIndex = (keymapIndex + 1) % KEYMAPS.length;
+  span.innerText = `[${KEYMAPS[keymapIndex]}]`;
+  localStorage.setItem("keymap", KEYMAPS[keymapIndex]);
+  applyKeymap();
+});
+document.getElementById("cycle-keymap").innerText = `[${KEYMAPS[keymapIndex]}]`;
+keymapConfig.addEventListener("change", () => {
+  localStorage.setItem("keymap-config", keymapConfig.value);
+  applyKeymap();
+});
+
+// The palette theme and configuration are kept in localStorage, the colors
+// are those of the XO-CHIP bitplanes: none, plane 1, plane 2 and both
+const PALETTES = ["chipo", "neon", "mono", "octo", "gameboy", "amber"];
+let paletteIndex = Math.max(0, PALETTES.indexOf(localStorage.getItem("palette")));
+let planeColors = [];
+const paletteConfig = document.getElementById("palette-config");
+paletteConfig.value = localStorage.getItem("palette-config") || "";
+const applyPalette = () => {
+  try {
+    planeColors = get_palette(PALETTES[paletteIndex], paletteConfig.value).split(",");
+    hideError();
+  } catch (err) {
+    showError(err);
+    return;
+  }
+  if (emu) {
+    emu.display();
+  } else {
+    clearScreen();
+  }
+};
+initEvent("cycle-palette", (span) => {
+  paletteIndex = (paletteIndex + 1) % PALETTES.length;
+  span.innerText = `[${PALETTES[paletteIndex]}]`;
+  localStorage.setItem("palette", PALETTES[paletteIndex]);
+  applyPalette();
+});
+document.getElementById("cycle-palette").innerText = `[${PALETTES[paletteIndex]}]`;
+paletteConfig.addEventListener("change", () => {
+  localStorage.setItem("palette-config", paletteConfig.value);
+  applyPalette();
+});
+
+// Files picked by the user can be included by the program with .include and
+// .incbin, by their name
+let includeFiles = null;
+document.getElementById("include-files").addEventListener("change", async (event) => {
+  for (const file of event.target.files) {
+    add_file(includeFiles, file.name, new Uint8Array(await file.arrayBuffer()));
+  }
+});
+
+// Movies record the key presses from a restart of the program, the last one
+// is kept in localStorage to be replayed
+initEvent("record-movie", () => {
+  if (emu) emu.startRecording();
+});
+initEvent("save-movie", () => {
+  const movie = emu && emu.stopRecording();
+  if (!movie) return;
+  localStorage.setItem("saved-movie", movie);
+  const url = URL.createObjectURL(new Blob([movie], { type: "text/plain" })),
+        a = document.createElement("a");
+  a.href = url;
+  a.download = "movie.txt";
+  document.body.appendChild(a);
+  a.click();
+  setTimeout(function() {
+    document.body.removeChild(a);
+    window.URL.revokeObjectURL(url);
+  });
+});
+
 let autoRun = true;
 initEvent("toggle-auto-run", (span) => {
   autoRun = !autoRun;
@@ -124,6 +308,7 @@ CodeMirror.defineSimpleMode("asm", {
 
 let codeValue = localStorage.getItem("saved-code") || "";
 let running = false;
+let rewinding = false;
 
 const canvas = document.getElementById("canvas");
 canvas.width = 64 * SCALE;
@@ -173,6 +358,11 @@ async function run() {
 
 	document.addEventListener("keydown", function(event) {
 		const key = event.code.toLowerCase();
+		// Holding backspace outside of the editor rewinds the emulator
+		if (key === "backspace" && !event.target.closest(".CodeMirror")) {
+			rewinding = true;
+			return;
+		}
 
 		if (emu) {
 			emu.setKeyDown(key);
@@ -180,6 +370,10 @@ async function run() {
 	});
 	document.addEventListener("keyup", function(event) {
 		const key = event.code.toLowerCase();
+		if (key === "backspace") {
+			rewinding = false;
+			return;
+		}
 
 		if (emu) {
 			emu.setKeyUp(key);
@@ -196,12 +390,14 @@ async function run() {
   }
 
   await init();
+  includeFiles = new_files();
+  applyPalette();
 
-  const startEmu = () => {
+  const startEmu = (movie) => {
     let code_buffer = new Uint8Array(MAX_SIZE);
     const text = codeMirror.getValue();
     try {
-      let length = compile(text, code_buffer);
+      let length = compile(text, includeFiles, code_buffer);
 			code_buffer = code_buffer.slice(0, length);
     } catch (err) {
       showError(err);
@@ -212,26 +408,33 @@ async function run() {
 		if (emu !== null) {
 			emu.destroy();
 		}
-    emu = new Emulator(code_buffer);
+    try {
+      emu = new Emulator(code_buffer, movie);
+    } catch (err) {
+      showError(err);
+      return;
+    }
+    applyKeymap();
     running = true;
 		audioCtx = new (window.AudioContext || window.webkitAudioContext)();
 
-    function loop() {
+    let lastTimestamp;
+    function loop(timestamp) {
       if (!emu.isPlaying() || !running) {
         return;
       }
 
-      for (let i = 0; i < 10; i++) {
-        emu.cycle();
-      }
-      emu.decrementRegisters();
+      const elapsed = lastTimestamp === undefined ? 0 : timestamp - lastTimestamp;
+      lastTimestamp = timestamp;
+      emu.setRewinding(rewinding);
+      emu.run(elapsed);
       emu.display();
 			emu.doBuzz();
 
       requestAnimationFrame(loop);
     }
 
-    loop();
+    requestAnimationFrame(loop);
   };
 
   const runAgain = async () => {
@@ -242,7 +445,7 @@ async function run() {
 
 	document.querySelector(".download").addEventListener("click", async () => {
 		let data = new Uint8Array(MAX_SIZE);
-		const length = compile(codeMirror.getValue(), data);
+		const length = compile(codeMirror.getValue(), includeFiles, data);
 		data = data.slice(0, length);
 		const file = new Blob([data]);
 		const url = URL.createObjectURL(file),
@@ -265,6 +468,13 @@ async function run() {
 		codeMirror.setValue(result);
 	});
   initEvent("run", runAgain);
+  initEvent("replay-movie", async () => {
+    const movie = localStorage.getItem("saved-movie");
+    if (!movie) return showError("no movie saved");
+    stop();
+    await new Promise((res) => setTimeout(res));
+    startEmu(movie);
+  });
   codeMirror.on("change", () => {
     if (autoSave) {
       save();
@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+pub const TIMER_FREQUENCY: u64 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_SECOND: u64 = 600;
+// Longer pauses, like a window being dragged, are not caught up
+const MAX_CATCH_UP: Duration = Duration::from_millis(250);
+// Speeds are kept in percents so that scaled time stays exact
+const MIN_SPEED_PERCENT: u128 = 1;
+const MAX_SPEED_PERCENT: u128 = 10_000;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+// Drives the emulation in 60 Hz frames: each frame executes its share of
+// the instructions per second then decrements the timers once. Frames always
+// have the same instructions whatever the host timing, so that inputs
+// applied between frames replay exactly.
+// The emulated time can run slower or faster than the real time, or be
+// paused and stepped one frame at a time.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    instructions_per_second: u64,
+    // Emulated time in nanoseconds, frames are due as it passes their end
+    elapsed: u128,
+    frame: u64,
+    speed_percent: u128,
+    // Hundredths of nanosecond left over when scaling by the speed
+    speed_remainder: u128,
+    paused: bool,
+}
+
+impl Clock {
+    pub fn new(instructions_per_second: u64) -> Self {
+        Clock {
+            instructions_per_second,
+            elapsed: 0,
+            frame: 0,
+            speed_percent: 100,
+            speed_remainder: 0,
+            paused: false,
+        }
+    }
+
+    pub fn instructions_per_second(&self) -> u64 {
+        self.instructions_per_second
+    }
+
+    pub fn set_instructions_per_second(&mut self, instructions_per_second: u64) {
+        self.instructions_per_second = instructions_per_second;
+    }
+
+    // Number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Emulated seconds per real second, 1 being the normal speed
+    pub fn speed(&self) -> f64 {
+        self.speed_percent as f64 / 100.0
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed_percent =
+            ((speed * 100.0).round() as u128).clamp(MIN_SPEED_PERCENT, MAX_SPEED_PERCENT);
+        self.speed_remainder = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    // Advances the clock by the real time elapsed since the last call and
+    // returns the number of frames now due, to run with next_frame. Several
+    // frames mean the host dropped some. Nothing is due while paused.
+    pub fn advance(&mut self, elapsed: Duration) -> usize {
+        if self.paused {
+            return 0;
+        }
+        let scaled =
+            elapsed.min(MAX_CATCH_UP).as_nanos() * self.speed_percent + self.speed_remainder;
+        self.elapsed += scaled / 100;
+        self.speed_remainder = scaled % 100;
+        let due = self.elapsed * TIMER_FREQUENCY as u128 / NANOS_PER_SECOND;
+        due.saturating_sub(self.frame as u128) as usize
+    }
+
+    // Starts the next frame and returns the number of instructions to run
+    // before decrementing the timers. It can be called without advance to
+    // step a paused emulation, or by frontends driven by frames.
+    pub fn next_frame(&mut self) -> usize {
+        let frame = self.frame as u128;
+        let ips = self.instructions_per_second as u128;
+        let frequency = TIMER_FREQUENCY as u128;
+        let cycles = (frame + 1) * ips / frequency - frame * ips / frequency;
+
+        self.frame += 1;
+        let end = (frame + 1) * NANOS_PER_SECOND;
+        self.elapsed = self.elapsed.max(end.div_ceil(frequency));
+        cycles as usize
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new(DEFAULT_INSTRUCTIONS_PER_SECOND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the frames due and returns them with the instructions executed
+    fn run(clock: &mut Clock, elapsed: Duration) -> (usize, usize) {
+        let frames = clock.advance(elapsed);
+        let cycles = (0..frames).map(|_| clock.next_frame()).sum();
+        (frames, cycles)
+    }
+
+    #[test]
+    fn test_one_second() {
+        let mut clock = Clock::new(700);
+        let (mut frames, mut cycles) = (0, 0);
+        // 8ms steps don't line up with the 60 Hz frames
+        for _ in 0..125 {
+            let (f, c) = run(&mut clock, Duration::from_millis(8));
+            frames += f;
+            cycles += c;
+        }
+        assert_eq!((frames, cycles), (60, 700));
+        assert_eq!(clock.frame(), 60);
+    }
+
+    #[test]
+    fn test_dropped_frames() {
+        let mut clock = Clock::new(600);
+        assert_eq!(run(&mut clock, Duration::from_millis(50)), (3, 30));
+        // Only a quarter of a second is caught up after a long pause
+        assert_eq!(run(&mut clock, Duration::from_secs(10)).0, 15);
+    }
+
+    #[test]
+    fn test_next_frame() {
+        let mut clock = Clock::new(90);
+        let cycles = (0..4).map(|_| clock.next_frame()).collect::<Vec<usize>>();
+        assert_eq!(cycles, vec![1, 2, 1, 2]);
+        clock.set_instructions_per_second(1200);
+        assert_eq!(clock.next_frame(), 20);
+        assert_eq!(clock.advance(Duration::from_millis(1)), 0);
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut clock = Clock::new(600);
+        clock.set_speed(0.5);
+        assert_eq!(run(&mut clock, Duration::from_millis(100)).0, 3);
+        clock.set_speed(4.0);
+        assert_eq!(run(&mut clock, Duration::from_millis(100)), (24, 240));
+        clock.set_speed(0.0);
+        assert_eq!(clock.speed(), 0.01);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut clock = Clock::new(600);
+        assert_eq!(run(&mut clock, Duration::from_millis(25)).0, 1);
+        clock.set_paused(true);
+        assert_eq!(clock.advance(Duration::from_secs(1)), 0);
+        // Stepping a frame ends it where it would have ended running
+        assert_eq!(clock.next_frame(), 10);
+        assert_eq!(clock.frame(), 2);
+        clock.set_paused(false);
+        assert_eq!(run(&mut clock, Duration::from_millis(50)).0, 3);
+    }
+}
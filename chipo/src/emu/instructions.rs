@@ -25,8 +25,8 @@ pub enum Instruction {
     Xor(Vx, Vx),
     Sub(Vx, Vx),
     SubSelf(Vx, Vx), // Todo: find better name
-    RightShift(Vx),
-    LeftShift(Vx),
+    RightShift(Vx, Vx),
+    LeftShift(Vx, Vx),
     SetAddr(Addr),
     Jump(Addr),
     Rand(Vx, Val),
@@ -42,22 +42,63 @@ pub enum Instruction {
     BCD(Vx),
     MemDump(Vx),
     MemLoad(Vx),
+    // SUPER-CHIP 1.1 instructions
+    ScrollDown(Val),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
+    BigFontLoad(Vx),
+    SaveFlags(Vx),
+    LoadFlags(Vx),
+    // XO-CHIP instructions
+    LongSetAddr(Addr),
+    SaveRange(Vx, Vx),
+    LoadRange(Vx, Vx),
+    SelectPlane(Val),
+    LoadAudio,
+    SetPitch(Vx),
 }
 
 impl Instruction {
+    // The XO-CHIP F000 NNNN instruction is the only one spanning 4 bytes
+    pub fn is_long(instr: u16) -> bool {
+        instr == 0xF000
+    }
+
+    pub fn from_long(instr: u16, operand: u16) -> Result<Self> {
+        if Self::is_long(instr) {
+            Ok(Instruction::LongSetAddr(operand as Addr))
+        } else {
+            Self::from(instr)
+        }
+    }
+
     pub fn from(instr: u16) -> Result<Self> {
         use Instruction::*;
         match instr & 0xF000 {
             0x0000 => Ok(match instr & 0x00FF {
                 0x00E0 => DisplayClear,
                 0x00EE => Return,
+                0x00FB => ScrollRight,
+                0x00FC => ScrollLeft,
+                0x00FD => Exit,
+                0x00FE => LowRes,
+                0x00FF => HighRes,
+                n if n & 0xF0 == 0xC0 => ScrollDown(as_small_val(instr)),
                 _ => Raw(instr),
             }),
             0x1000 => Ok(GoTo(as_addr(instr))),
             0x2000 => Ok(Call(as_addr(instr))),
             0x3000 => Ok(IfEq(as_vx(instr), as_val(instr))),
             0x4000 => Ok(IfNeq(as_vx(instr), as_val(instr))),
-            0x5000 => Ok(IfEqRg(as_vx(instr), as_vy(instr))),
+            0x5000 => match instr & 0x000F {
+                0 => Ok(IfEqRg(as_vx(instr), as_vy(instr))),
+                2 => Ok(SaveRange(as_vx(instr), as_vy(instr))),
+                3 => Ok(LoadRange(as_vx(instr), as_vy(instr))),
+                _ => Err(ChipoError::UnknownOpCodeErr(instr)),
+            },
             0x6000 => Ok(Set(as_vx(instr), as_val(instr))),
             0x7000 => Ok(Add(as_vx(instr), as_val(instr))),
             0x8000 => match instr & 0x000F {
@@ -67,9 +108,9 @@ impl Instruction {
                 3 => Ok(Xor(as_vx(instr), as_vy(instr))),
                 4 => Ok(AddRg(as_vx(instr), as_vy(instr))),
                 5 => Ok(Sub(as_vx(instr), as_vy(instr))),
-                6 => Ok(RightShift(as_vx(instr))),
+                6 => Ok(RightShift(as_vx(instr), as_vy(instr))),
                 7 => Ok(SubSelf(as_vx(instr), as_vy(instr))),
-                0xE => Ok(LeftShift(as_vx(instr))),
+                0xE => Ok(LeftShift(as_vx(instr), as_vy(instr))),
                 _ => Err(ChipoError::UnknownOpCodeErr(instr)),
             },
             0x9000 => Ok(IfNeqRg(as_vx(instr), as_vy(instr))),
@@ -83,15 +124,21 @@ impl Instruction {
                 _ => Err(ChipoError::UnknownOpCodeErr(instr)),
             },
             0xF000 => match instr & 0x00FF {
+                0x01 => Ok(SelectPlane(as_vx(instr) as Val)),
+                0x02 if as_vx(instr) == 0 => Ok(LoadAudio),
                 0x07 => Ok(GetTimer(as_vx(instr))),
                 0x0A => Ok(GetKeyOp(as_vx(instr))),
                 0x15 => Ok(SetTimer(as_vx(instr))),
                 0x18 => Ok(SetSoundTimer(as_vx(instr))),
                 0x1E => Ok(AddToI(as_vx(instr))),
                 0x29 => Ok(FontLoad(as_vx(instr))),
+                0x30 => Ok(BigFontLoad(as_vx(instr))),
                 0x33 => Ok(BCD(as_vx(instr))),
+                0x3A => Ok(SetPitch(as_vx(instr))),
                 0x55 => Ok(MemDump(as_vx(instr))),
                 0x65 => Ok(MemLoad(as_vx(instr))),
+                0x75 => Ok(SaveFlags(as_vx(instr))),
+                0x85 => Ok(LoadFlags(as_vx(instr))),
                 _ => Err(ChipoError::UnknownOpCodeErr(instr)),
             },
             _ => Err(ChipoError::UnknownOpCodeErr(instr)),
@@ -118,13 +165,17 @@ impl Instruction {
             Xor(vx, vy) => ((0x8 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 3) as u16,
             AddRg(vx, vy) => ((0x8 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 4) as u16,
             Sub(vx, vy) => ((0x8 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 5) as u16,
-            RightShift(vx) => ((0x8 << 12) + ((*vx & 0xF) << 8) + 6) as u16,
+            RightShift(vx, vy) => {
+                ((0x8 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 6) as u16
+            }
             SubSelf(vx, vy) => ((0x8 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 7) as u16,
-            LeftShift(vx) => ((0x8 << 12) + ((*vx & 0xF) << 8) + 0xE) as u16,
+            LeftShift(vx, vy) => {
+                ((0x8 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 0xE) as u16
+            }
             IfNeqRg(vx, vy) => ((0x9 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4)) as u16,
             SetAddr(addr) => ((0xA << 12) + *addr as u16),
             Jump(addr) => ((0xB << 12) + *addr as u16),
-            Rand(vx, byte) => ((0xB << 12) + ((*vx & 0xF) << 8) + *byte as usize) as u16,
+            Rand(vx, byte) => ((0xC << 12) + ((*vx & 0xF) << 8) + *byte as usize) as u16,
             Disp(vx, vy, nibble) => {
                 (0xD << 12)
                     + ((*vx & 0xF) << 8) as u16
@@ -141,6 +192,38 @@ impl Instruction {
             BCD(vx) => ((0xF << 12) + ((*vx & 0xF) << 8) as u16 + 0x33),
             MemDump(vx) => ((0xF << 12) + ((*vx & 0xF) << 8) as u16 + 0x55),
             MemLoad(vx) => ((0xF << 12) + ((*vx & 0xF) << 8) as u16 + 0x65),
+            ScrollDown(nibble) => 0x00C0 + (*nibble & 0xF) as u16,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            LowRes => 0x00FE,
+            HighRes => 0x00FF,
+            BigFontLoad(vx) => (0xF << 12) + ((*vx & 0xF) << 8) as u16 + 0x30,
+            SaveFlags(vx) => (0xF << 12) + ((*vx & 0xF) << 8) as u16 + 0x75,
+            LoadFlags(vx) => (0xF << 12) + ((*vx & 0xF) << 8) as u16 + 0x85,
+            LongSetAddr(..) => 0xF000,
+            SaveRange(vx, vy) => ((0x5 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 2) as u16,
+            LoadRange(vx, vy) => ((0x5 << 12) + ((*vx & 0xF) << 8) + ((*vy & 0xF) << 4) + 3) as u16,
+            SelectPlane(mask) => (0xF << 12) + ((*mask as u16 & 0xF) << 8) + 0x01,
+            LoadAudio => 0xF002,
+            SetPitch(vx) => (0xF << 12) + ((*vx & 0xF) << 8) as u16 + 0x3A,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let bin = self.to_bin();
+        match self {
+            Instruction::LongSetAddr(addr) => {
+                vec![(bin >> 8) as u8, bin as u8, (addr >> 8) as u8, *addr as u8]
+            }
+            _ => vec![(bin >> 8) as u8, bin as u8],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LongSetAddr(..) => 4,
+            _ => 2,
         }
     }
 
@@ -163,9 +246,11 @@ impl Instruction {
             Xor(vx, vy) => format!("xor v{:X}, v{:X}", vx, vy),
             AddRg(vx, vy) => format!("add v{:X}, v{:X}", vx, vy),
             Sub(vx, vy) => format!("sub v{:X}, v{:X}", vx, vy),
-            RightShift(vx) => format!("shr v{:X}", vx),
+            RightShift(vx, vy) if vx == vy => format!("shr v{:X}", vx),
+            RightShift(vx, vy) => format!("shr v{:X}, v{:X}", vx, vy),
             SubSelf(vx, vy) => format!("subn v{:X}, v{:X}", vx, vy),
-            LeftShift(vx) => format!("shl v{:X}", vx),
+            LeftShift(vx, vy) if vx == vy => format!("shl v{:X}", vx),
+            LeftShift(vx, vy) => format!("shl v{:X}, v{:X}", vx, vy),
             IfNeqRg(vx, vy) => format!("sne v{:X}, v{:X}", vx, vy),
             SetAddr(addr) => format!("ld i, 0x{:02X}", addr),
             Jump(addr) => format!("jp 0x{:2X}", addr),
@@ -182,6 +267,21 @@ impl Instruction {
             BCD(vx) => format!("ld b, v{:X}", vx),
             MemDump(vx) => format!("ld [i], v{:X}", vx),
             MemLoad(vx) => format!("ld v{:X}, [i]", vx),
+            ScrollDown(nibble) => format!("scd 0x{:02X}", nibble),
+            ScrollRight => "scr".to_string(),
+            ScrollLeft => "scl".to_string(),
+            Exit => "exit".to_string(),
+            LowRes => "low".to_string(),
+            HighRes => "high".to_string(),
+            BigFontLoad(vx) => format!("ld hf, v{:X}", vx),
+            SaveFlags(vx) => format!("ld r, v{:X}", vx),
+            LoadFlags(vx) => format!("ld v{:X}, r", vx),
+            LongSetAddr(addr) => format!("ld i, long 0x{:04X}", addr),
+            SaveRange(vx, vy) => format!("save v{:X}, v{:X}", vx, vy),
+            LoadRange(vx, vy) => format!("load v{:X}, v{:X}", vx, vy),
+            SelectPlane(mask) => format!("plane {}", mask),
+            LoadAudio => "audio".to_string(),
+            SetPitch(vx) => format!("ld pitch, v{:X}", vx),
             _ => format!("unimplemented {:?}.to_asm()", self),
         }
     }
@@ -239,6 +339,43 @@ mod tests {
         assert_eq!(Instruction::from(0xB999).unwrap().to_bin(), 0xB999);
         // LD I 0xFFF
         assert_eq!(Instruction::from(0xAFFF).unwrap().to_bin(), 0xAFFF);
+        // RND V3 0x0F
+        assert_eq!(Instruction::from(0xC30F).unwrap().to_bin(), 0xC30F);
+    }
+
+    #[test]
+    fn test_from_to_bin_schip() {
+        // SCD 4
+        assert_eq!(Instruction::from(0x00C4).unwrap().to_bin(), 0x00C4);
+        // SCR, SCL, EXIT, LOW, HIGH
+        for op in &[0x00FB, 0x00FC, 0x00FD, 0x00FE, 0x00FF] {
+            assert_eq!(Instruction::from(*op).unwrap().to_bin(), *op);
+        }
+        // LD HF V3
+        assert_eq!(Instruction::from(0xF330).unwrap().to_bin(), 0xF330);
+        // LD R V7
+        assert_eq!(Instruction::from(0xF775).unwrap().to_bin(), 0xF775);
+        // LD V7 R
+        assert_eq!(Instruction::from(0xF785).unwrap().to_bin(), 0xF785);
+    }
+
+    #[test]
+    fn test_from_to_bin_xochip() {
+        // SAVE V1 V4
+        assert_eq!(Instruction::from(0x5142).unwrap().to_bin(), 0x5142);
+        // LOAD V1 V4
+        assert_eq!(Instruction::from(0x5143).unwrap().to_bin(), 0x5143);
+        // PLANE 3
+        assert_eq!(Instruction::from(0xF301).unwrap().to_bin(), 0xF301);
+        // AUDIO
+        assert_eq!(Instruction::from(0xF002).unwrap().to_bin(), 0xF002);
+        // LD PITCH V2
+        assert_eq!(Instruction::from(0xF23A).unwrap().to_bin(), 0xF23A);
+        // LD I LONG 0x1234
+        let long = Instruction::from_long(0xF000, 0x1234).unwrap();
+        assert_eq!(long, Instruction::LongSetAddr(0x1234));
+        assert_eq!(long.to_bytes(), vec![0xF0, 0x00, 0x12, 0x34]);
+        assert_eq!(long.size(), 4);
     }
 
     #[test]
@@ -1,19 +0,0 @@
-pub enum Keycode {
-    Num1,
-    Num2,
-    Num3,
-    Num4,
-    A,
-    Z,
-    E,
-    R,
-    Q,
-    S,
-    D,
-    F,
-    W,
-    X,
-    C,
-    V,
-    Other,
-}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::emu::state::crc32;
+use crate::error::{ChipoError, Result};
+
+// Keys of the hexadecimal keypad row by row, the presets list host keys in
+// the same order
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+// From http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#keyboard
+const KEYPAD: [usize; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+const QWERTY: [&str; 16] = [
+    "1", "2", "3", "4", "q", "w", "e", "r", "a", "s", "d", "f", "z", "x", "c", "v",
+];
+const AZERTY: [&str; 16] = [
+    "1", "2", "3", "4", "a", "z", "e", "r", "q", "s", "d", "f", "w", "x", "c", "v",
+];
+const NUMPAD: [&str; 16] = [
+    "num7", "num8", "num9", "num/", "num4", "num5", "num6", "num*", "num1", "num2", "num3", "num-",
+    "num0", "num.", "numenter", "num+",
+];
+
+// Maps the names of host keys to the keys of the hexadecimal keypad.
+// Key names are lowercase: letters and digits are named after themselves,
+// numpad keys are num0 to num9, num/, num*, num-, num+, num. and numenter,
+// and frontends name the other keys as they like, like up or space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    keys: HashMap<String, usize>,
+}
+
+impl Keymap {
+    fn layout(names: &[&str; 16]) -> Self {
+        Keymap {
+            keys: names
+                .iter()
+                .zip(KEYPAD.iter())
+                .map(|(name, &key)| (name.to_string(), key))
+                .collect(),
+        }
+    }
+
+    pub fn qwerty() -> Self {
+        Keymap::layout(&QWERTY)
+    }
+
+    pub fn azerty() -> Self {
+        Keymap::layout(&AZERTY)
+    }
+
+    pub fn numpad() -> Self {
+        Keymap::layout(&NUMPAD)
+    }
+
+    pub fn empty() -> Self {
+        Keymap {
+            keys: HashMap::new(),
+        }
+    }
+
+    // Keypad key of a host key, None when it is not mapped
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.keys.get(&name.to_lowercase()).copied()
+    }
+
+    pub fn bind(&mut self, name: &str, key: usize) {
+        self.keys.insert(name.to_lowercase(), key & 0xF);
+    }
+
+    pub fn unbind(&mut self, name: &str) {
+        self.keys.remove(&name.to_lowercase());
+    }
+
+    // Applies a keymap configuration, made of lines like
+    //   preset azerty
+    //   space = 5
+    //   m = none
+    // The lines after a [<rom>] header only apply to the rom with this file
+    // name or with this CRC-32 in hexadecimal, and # starts a comment.
+    pub fn configure(&mut self, config: &str, rom_name: Option<&str>, rom: &[u8]) -> Result<()> {
+        let checksum = format!("{:08x}", crc32(rom));
+        let mut applies = true;
+        for line in config.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || ChipoError::InvalidKeymap(line.to_string());
+
+            if let Some(section) = line.strip_prefix('[') {
+                let section = section.strip_suffix(']').ok_or_else(invalid)?.trim();
+                applies = Some(section) == rom_name || section.to_lowercase() == checksum;
+            } else if !applies {
+                continue;
+            } else if let Some(preset) = line.strip_prefix("preset ") {
+                *self = preset.trim().parse()?;
+            } else if let Some((name, key)) = line.split_once('=') {
+                let (name, key) = (name.trim(), key.trim());
+                if name.is_empty() {
+                    return Err(invalid());
+                }
+                match key {
+                    "none" => self.unbind(name),
+                    _ => match usize::from_str_radix(key, 16) {
+                        Ok(key) if key <= 0xF => self.bind(name, key),
+                        _ => return Err(invalid()),
+                    },
+                }
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::qwerty()
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = ChipoError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "qwerty" => Ok(Keymap::qwerty()),
+            "azerty" => Ok(Keymap::azerty()),
+            "numpad" => Ok(Keymap::numpad()),
+            "none" => Ok(Keymap::empty()),
+            _ => Err(ChipoError::UnknownKeymap(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let qwerty = Keymap::qwerty();
+        assert_eq!(qwerty.get("x"), Some(0));
+        assert_eq!(qwerty.get("V"), Some(0xF));
+        assert_eq!(qwerty.get("4"), Some(0xC));
+        assert_eq!(qwerty.get("space"), None);
+
+        let azerty = "azerty".parse::<Keymap>().unwrap();
+        assert_eq!(azerty.get("a"), Some(4));
+        assert_eq!(azerty.get("w"), Some(0xA));
+
+        let numpad = "numpad".parse::<Keymap>().unwrap();
+        assert_eq!(numpad.get("num7"), Some(1));
+        assert_eq!(numpad.get("num."), Some(0));
+        assert!("dvorak".parse::<Keymap>().is_err());
+    }
+
+    #[test]
+    fn test_configure() {
+        let rom = [0x00, 0xE0];
+        let config = format!(
+            "# mine\nspace = 5\nq = none\n\n[pong.ch8]\npreset numpad\n\n[{:08X}]\nup = 2\n",
+            crc32(&rom)
+        );
+
+        let mut keymap = Keymap::qwerty();
+        keymap.configure(&config, Some("tetris.ch8"), &[]).unwrap();
+        assert_eq!(keymap.get("space"), Some(5));
+        assert_eq!(keymap.get("q"), None);
+        assert_eq!(keymap.get("up"), None);
+
+        let mut keymap = Keymap::qwerty();
+        keymap.configure(&config, Some("pong.ch8"), &rom).unwrap();
+        assert_eq!(keymap.get("space"), None);
+        assert_eq!(keymap.get("num5"), Some(5));
+        assert_eq!(keymap.get("up"), Some(2));
+
+        assert!(Keymap::qwerty().configure("a = 10", None, &[]).is_err());
+        assert!(Keymap::qwerty().configure("a 1", None, &[]).is_err());
+        assert!(Keymap::qwerty().configure("[pong", None, &[]).is_err());
+        assert!(Keymap::qwerty().configure("preset x", None, &[]).is_err());
+    }
+}
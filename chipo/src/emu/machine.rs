@@ -0,0 +1,538 @@
+use std::time::Duration;
+
+use crate::emu::{Clock, Movie, Proc, ProgramState, Replay, Rewind, Val, Watchpoint};
+use crate::error::Result;
+
+// Shows the screen of the processor
+pub trait Display {
+    // Draws the screen, after the frames that changed it
+    fn draw(&mut self, proc: &Proc);
+
+    // Called at the end of every frame with its number, for displays using
+    // the history of the screen
+    fn end_frame(&mut self, _proc: &Proc, _frame: u64) {}
+
+    // Whether the image changes from frame to frame with the same screen,
+    // like with fading pixels, to draw it after every frame
+    fn is_animated(&self) -> bool {
+        false
+    }
+
+    // Called before drawing when the program switched between the low and
+    // the high resolution, as (width, height)
+    fn resize(&mut self, _from: (usize, usize), _to: (usize, usize)) {}
+}
+
+// Plays the buzzer of the sound timer
+pub trait Audio {
+    // Whether the buzzer sounds, with the XO-CHIP pattern and its rate in
+    // bits per second when the program set one
+    fn set_sound(&mut self, on: bool, pattern: Option<&[Val; 16]>, rate: f32);
+}
+
+// Keyboards, controllers and whatever presses the keys of the keypad
+pub trait Input {
+    // Keys pressed or released since the last call, as (key, down), polled
+    // before each frame with its number
+    fn poll(&mut self, frame: u64) -> Vec<(usize, bool)>;
+}
+
+// Frontends without a screen, a sound or keys, like tests
+impl Display for () {
+    fn draw(&mut self, _proc: &Proc) {}
+}
+
+impl Audio for () {
+    fn set_sound(&mut self, _on: bool, _pattern: Option<&[Val; 16]>, _rate: f32) {}
+}
+
+impl Input for () {
+    fn poll(&mut self, _frame: u64) -> Vec<(usize, bool)> {
+        vec![]
+    }
+}
+
+// Key events queued by the frontend
+impl Input for Vec<(usize, bool)> {
+    fn poll(&mut self, _frame: u64) -> Vec<(usize, bool)> {
+        std::mem::take(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineState {
+    Running,
+    // The program returned from its main routine
+    Stopped,
+    // A watchpoint was reached, the rest of its frame runs with the next one
+    Watchpoint(Watchpoint),
+    // The replayed movie ended at this frame, the clock is now paused
+    ReplayFinished(u64),
+}
+
+// Runs a processor in real time for the frontends: the clock sets the frames
+// due, each frame runs its instructions then decrements the 60 Hz timers and
+// keys are pressed between frames, recorded in a movie or replayed from one.
+// Frontends only show the screen, play the sound and read the keys.
+pub struct Machine {
+    proc: Proc,
+    clock: Clock,
+    movie: Option<Movie>,
+    replay: Option<Replay>,
+    rewind: Option<Rewind>,
+    rewinding: bool,
+    // Whether frames ran since the display was last updated
+    ran: bool,
+    // Resolution the display was last drawn at
+    resolution: (usize, usize),
+    // Instructions left in the frame interrupted by a watchpoint, and the
+    // keys pressed meanwhile, which apply once it ends as they are recorded
+    owed: usize,
+    deferred_keys: Vec<(usize, bool)>,
+}
+
+impl Machine {
+    pub fn new(proc: Proc, clock: Clock) -> Self {
+        Machine {
+            resolution: (proc.width(), proc.height()),
+            proc,
+            clock,
+            movie: None,
+            replay: None,
+            rewind: None,
+            rewinding: false,
+            ran: false,
+            owed: 0,
+            deferred_keys: vec![],
+        }
+    }
+
+    // Keeps a history of frames to rewind
+    pub fn with_rewind(mut self, frames: usize) -> Self {
+        self.rewind = Some(Rewind::new(frames));
+        self
+    }
+
+    pub fn proc(&self) -> &Proc {
+        &self.proc
+    }
+
+    pub fn proc_mut(&mut self) -> &mut Proc {
+        &mut self.proc
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    // Records the key events from now on in movie
+    pub fn start_recording(&mut self, movie: Movie) {
+        self.movie = Some(movie);
+    }
+
+    // Stops recording and returns the movie, ending at the current frame
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        let mut movie = self.movie.take()?;
+        movie.finish(self.clock.frame());
+        Some(movie)
+    }
+
+    // Replays key events instead of the ones of the frontend
+    pub fn start_replay(&mut self, replay: Replay) {
+        self.replay = Some(replay);
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    // Rewinding and loading states would break the movie recorded or replayed
+    pub fn has_movie(&self) -> bool {
+        self.movie.is_some() || self.replay.is_some()
+    }
+
+    // Presses or releases a key before the next frame, recording it in the
+    // movie. Keys are ignored while a movie is replayed.
+    pub fn set_key(&mut self, key: usize, down: bool) {
+        if self.replay.is_some() {
+            return;
+        }
+        if self.owed > 0 {
+            self.deferred_keys.push((key, down));
+        } else {
+            self.proc.set_key(key, down);
+        }
+        if let Some(movie) = self.movie.as_mut() {
+            movie.record(self.clock.frame(), key, down);
+        }
+    }
+
+    // While rewinding, each frame steps back in the history instead of running
+    pub fn set_rewinding(&mut self, rewinding: bool) {
+        self.rewinding = rewinding && !self.has_movie();
+    }
+
+    // Whether a watchpoint stopped in the middle of a frame
+    pub fn is_mid_frame(&self) -> bool {
+        self.owed > 0
+    }
+
+    // Drops the end of an interrupted frame, when the state it ran on is
+    // replaced by one saved between frames
+    fn abandon_frame(&mut self) {
+        self.owed = 0;
+        for (key, down) in std::mem::take(&mut self.deferred_keys) {
+            self.proc.set_key(key, down);
+        }
+    }
+
+    // Steps back up to frames frames and returns how many were rewound
+    pub fn rewind(&mut self, frames: usize) -> Result<usize> {
+        if self.has_movie() {
+            return Ok(0);
+        }
+        self.abandon_frame();
+        match self.rewind.as_mut() {
+            Some(rewind) => rewind.rewind(&mut self.proc, frames),
+            None => Ok(0),
+        }
+    }
+
+    // Forgets the history, once the state was replaced
+    pub fn clear_rewind(&mut self) {
+        self.abandon_frame();
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
+    }
+
+    // Runs a single frame, executing instructions with cycle which frontends
+    // can use to hook debuggers
+    pub fn frame(
+        &mut self,
+        mut cycle: impl FnMut(&mut Proc) -> Result<ProgramState>,
+    ) -> Result<MachineState> {
+        self.ran = true;
+        if self.rewinding && self.rewind.is_some() {
+            self.abandon_frame();
+            self.clock.next_frame();
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.rewind(&mut self.proc, 1)?;
+            }
+            return Ok(MachineState::Running);
+        }
+
+        // An interrupted frame is finished before starting the next one
+        let cycles = if self.owed > 0 {
+            std::mem::take(&mut self.owed)
+        } else {
+            let frame = self.clock.frame();
+            if let Some(replay) = self.replay.as_mut() {
+                replay.apply(frame, &mut self.proc);
+                if replay.is_finished(frame) {
+                    self.replay = None;
+                    self.clock.set_paused(true);
+                    return Ok(MachineState::ReplayFinished(frame));
+                }
+            }
+            self.clock.next_frame()
+        };
+
+        let mut state = MachineState::Running;
+        for n in 0..cycles {
+            match cycle(&mut self.proc)? {
+                ProgramState::Continue => {}
+                ProgramState::Stop => return Ok(MachineState::Stopped),
+                // Stops right after the instruction so that pc points past it
+                ProgramState::Watchpoint(watchpoint) => {
+                    self.owed = cycles - n - 1;
+                    if self.owed > 0 {
+                        return Ok(MachineState::Watchpoint(watchpoint));
+                    }
+                    state = MachineState::Watchpoint(watchpoint);
+                }
+            }
+        }
+        self.proc.decrement_registers();
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.push(&self.proc);
+        }
+        for (key, down) in std::mem::take(&mut self.deferred_keys) {
+            self.proc.set_key(key, down);
+        }
+        Ok(state)
+    }
+
+    // Plays the sound and draws the screen if it changed
+    pub fn present(&mut self, display: &mut dyn Display, audio: &mut dyn Audio) {
+        let resolution = (self.proc.width(), self.proc.height());
+        if resolution != self.resolution {
+            display.resize(self.resolution, resolution);
+            self.resolution = resolution;
+            self.proc.should_render = true;
+        }
+        audio.set_sound(
+            self.proc.should_buzz() && !self.clock.is_paused(),
+            self.proc.audio_pattern(),
+            self.proc.audio_rate(),
+        );
+        if self.proc.should_render || (self.ran && display.is_animated()) {
+            self.proc.should_render = false;
+            display.draw(&self.proc);
+        }
+        self.ran = false;
+    }
+
+    // Runs frames whatever the clock, applying the keys of the input before
+    // each one, until one doesn't end normally
+    pub fn run(
+        &mut self,
+        frames: usize,
+        display: &mut dyn Display,
+        audio: &mut dyn Audio,
+        input: &mut dyn Input,
+    ) -> Result<MachineState> {
+        self.run_with(frames, display, audio, input, |proc, _, _| proc.cycle())
+    }
+
+    // Same as run, executing instructions with cycle which frontends can use
+    // to hook debuggers. It gets the display and the audio to show the
+    // machine while a debugger waits.
+    pub fn run_with(
+        &mut self,
+        frames: usize,
+        display: &mut dyn Display,
+        audio: &mut dyn Audio,
+        input: &mut dyn Input,
+        mut cycle: impl FnMut(&mut Proc, &mut dyn Display, &mut dyn Audio) -> Result<ProgramState>,
+    ) -> Result<MachineState> {
+        let mut state = MachineState::Running;
+        for _ in 0..frames {
+            for (key, down) in input.poll(self.clock.frame()) {
+                self.set_key(key, down);
+            }
+            state = self.frame(|proc| cycle(proc, &mut *display, &mut *audio))?;
+            match state {
+                MachineState::Running => {}
+                MachineState::Watchpoint(_) => {
+                    if !self.is_mid_frame() {
+                        display.end_frame(&self.proc, self.clock.frame());
+                    }
+                    break;
+                }
+                _ => break,
+            }
+            display.end_frame(&self.proc, self.clock.frame());
+        }
+        self.present(display, audio);
+        Ok(state)
+    }
+
+    // Runs the frames due after elapsed time
+    pub fn update(
+        &mut self,
+        elapsed: Duration,
+        display: &mut dyn Display,
+        audio: &mut dyn Audio,
+        input: &mut dyn Input,
+    ) -> Result<MachineState> {
+        let frames = self.clock.advance(elapsed);
+        self.run(frames, display, audio, input)
+    }
+
+    // Same as update, executing instructions with cycle as run_with does
+    pub fn update_with(
+        &mut self,
+        elapsed: Duration,
+        display: &mut dyn Display,
+        audio: &mut dyn Audio,
+        input: &mut dyn Input,
+        cycle: impl FnMut(&mut Proc, &mut dyn Display, &mut dyn Audio) -> Result<ProgramState>,
+    ) -> Result<MachineState> {
+        let frames = self.clock.advance(elapsed);
+        self.run_with(frames, display, audio, input, cycle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+
+    // Test doubles remembering what the machine asked for
+    #[derive(Default)]
+    struct Screen {
+        draws: usize,
+        frames: Vec<u64>,
+    }
+
+    impl Display for Screen {
+        fn draw(&mut self, _proc: &Proc) {
+            self.draws += 1;
+        }
+
+        fn end_frame(&mut self, _proc: &Proc, frame: u64) {
+            self.frames.push(frame);
+        }
+    }
+
+    #[derive(Default)]
+    struct Buzzer {
+        on: bool,
+    }
+
+    impl Audio for Buzzer {
+        fn set_sound(&mut self, on: bool, _pattern: Option<&[Val; 16]>, _rate: f32) {
+            self.on = on;
+        }
+    }
+
+    // Waits for a key, then shows its digit and buzzes for a second
+    fn machine() -> Machine {
+        let rom = compile(
+            r#".code
+    ld v0, k
+    ld f, v0
+    drw v1, v1, 5
+    ld v2, 60
+    ld st, v2
+wait:
+    jp wait"#,
+        )
+        .unwrap();
+        Machine::new(Proc::binary(&rom).unwrap(), Clock::new(600))
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut machine = machine();
+        let (mut screen, mut buzzer) = (Screen::default(), Buzzer::default());
+        let frame = Duration::from_millis(17);
+
+        let state = machine
+            .update(frame * 3, &mut screen, &mut buzzer, &mut ())
+            .unwrap();
+        assert_eq!(state, MachineState::Running);
+        assert_eq!(screen.frames, vec![1, 2, 3]);
+        assert_eq!(machine.proc().registers()[0], 0);
+        assert!(!buzzer.on);
+
+        let mut keys = vec![(7, true)];
+        machine
+            .update(frame, &mut screen, &mut buzzer, &mut keys)
+            .unwrap();
+        assert!(keys.is_empty());
+        assert_eq!(machine.proc().registers()[0], 7);
+        assert!(buzzer.on);
+        let draws = screen.draws;
+        assert!(draws >= 1);
+
+        // Nothing is drawn again until the screen changes
+        machine.run(1, &mut screen, &mut buzzer, &mut ()).unwrap();
+        assert_eq!(screen.draws, draws);
+        machine.clock_mut().set_paused(true);
+        machine
+            .update(frame * 10, &mut screen, &mut buzzer, &mut ())
+            .unwrap();
+        assert_eq!(screen.frames.len(), 5);
+        assert!(!buzzer.on);
+    }
+
+    #[test]
+    fn test_movie() {
+        let mut machine = machine().with_rewind(10);
+        let movie = Movie::new(&[], machine.proc(), machine.clock());
+        machine.start_recording(movie);
+        machine
+            .run(2, &mut (), &mut (), &mut vec![(3, true)])
+            .unwrap();
+        machine.set_key(3, false);
+        machine.set_rewinding(true);
+        machine.run(2, &mut (), &mut (), &mut ()).unwrap();
+        assert_eq!(machine.rewind(1).unwrap(), 0);
+
+        let movie = machine.stop_recording().unwrap();
+        assert_eq!(movie.frames, 4);
+        assert_eq!(movie.events.len(), 2);
+        assert_eq!(movie.events[1].frame, 2);
+
+        let mut replay = self::machine();
+        replay.start_replay(movie.replay());
+        replay.set_key(5, true);
+        let states = (0..5)
+            .map(|_| replay.run(1, &mut (), &mut (), &mut ()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(states[4], MachineState::ReplayFinished(4));
+        assert_eq!(replay.proc().registers()[0], 3);
+        assert!(replay.clock().is_paused());
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut machine = machine().with_rewind(10);
+        machine
+            .run(3, &mut (), &mut (), &mut vec![(9, true)])
+            .unwrap();
+        assert_eq!(machine.proc().sound_timer(), 57);
+        machine.set_rewinding(true);
+        machine.run(2, &mut (), &mut (), &mut ()).unwrap();
+        assert_eq!(machine.clock().frame(), 5);
+        assert_eq!(machine.proc().sound_timer(), 59);
+        machine.set_rewinding(false);
+        machine.run(1, &mut (), &mut (), &mut ()).unwrap();
+        assert_eq!(machine.rewind(5).unwrap(), 1);
+        assert_eq!(machine.proc().sound_timer(), 59);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let rom = compile(
+            r#".code
+    ld v0, 30
+    ld dt, v0
+    ld v1, 1
+loop:
+    add v2, 1
+    jp loop"#,
+        )
+        .unwrap();
+        let run = |watch: bool| {
+            let mut proc = Proc::binary(&rom).unwrap();
+            proc.set_seed(0);
+            let mut machine = Machine::new(proc, Clock::new(600)).with_rewind(10);
+            if watch {
+                machine
+                    .proc_mut()
+                    .add_watchpoint(Watchpoint::Register(1, 1));
+            }
+            machine
+        };
+
+        let mut machine = run(true);
+        let state = machine.frame(Proc::cycle).unwrap();
+        assert_eq!(state, MachineState::Watchpoint(Watchpoint::Register(1, 1)));
+        // Execution stops right after `ld v1, 1`, before the timers tick
+        assert!(machine.is_mid_frame());
+        assert_eq!(machine.proc().pc(), 0x206);
+        assert_eq!(machine.proc().delay_timer(), 30);
+
+        // Resuming ends the frame as if it never stopped
+        let mut screen = Screen::default();
+        // A key pressed meanwhile is applied after the frame, where it is recorded
+        machine.set_key(4, true);
+        machine.run(2, &mut screen, &mut (), &mut ()).unwrap();
+        assert_eq!(screen.frames, vec![1, 2]);
+        let mut expected = run(false);
+        expected.frame(Proc::cycle).unwrap();
+        expected.set_key(4, true);
+        expected.frame(Proc::cycle).unwrap();
+        assert_eq!(machine.proc().delay_timer(), 28);
+        assert_eq!(machine.proc().pc(), expected.proc().pc());
+        assert_eq!(machine.proc().save_state(), expected.proc().save_state());
+        assert_eq!(machine.rewind(2).unwrap(), 1);
+    }
+}
@@ -1,7 +1,23 @@
+mod clock;
 mod instructions;
-mod keycode;
+mod keymap;
+mod machine;
+mod movie;
 mod proc;
+mod quirks;
+mod random;
+mod rewind;
+mod state;
+mod watch;
 
+pub use clock::{Clock, DEFAULT_INSTRUCTIONS_PER_SECOND, TIMER_FREQUENCY};
 pub use instructions::{Addr, Instruction, Val, Vx};
-pub use keycode::Keycode;
+pub use keymap::Keymap;
+pub use machine::{Audio, Display, Input, Machine, MachineState};
+pub use movie::{KeyEvent, Movie, Replay};
 pub use proc::{Proc, ProgramState};
+pub use quirks::Quirks;
+pub use random::{Random, XorShift};
+pub use rewind::Rewind;
+pub use state::crc32;
+pub use watch::Watchpoint;
@@ -0,0 +1,250 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::emu::state::crc32;
+use crate::emu::{Clock, Proc, Quirks};
+use crate::error::{ChipoError, Result};
+
+const HEADER: &str = "chipo-movie 1";
+
+// Key of the hexadecimal keypad pressed or released before a frame ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: usize,
+    pub down: bool,
+}
+
+// Key events of a run with everything needed to replay it exactly: the rom
+// checksum, the random generator seed, the quirks and the speed.
+// Movies are saved as text so that they can be attached to bug reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_checksum: u32,
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub instructions_per_second: u64,
+    // Length of the run in frames
+    pub frames: u64,
+    pub events: Vec<KeyEvent>,
+}
+
+impl Movie {
+    // Starts recording a run of rom from the current state of proc
+    pub fn new(rom: &[u8], proc: &Proc, clock: &Clock) -> Self {
+        Movie {
+            rom_checksum: crc32(rom),
+            seed: proc.seed(),
+            quirks: proc.quirks(),
+            instructions_per_second: clock.instructions_per_second(),
+            frames: clock.frame(),
+            events: vec![],
+        }
+    }
+
+    pub fn record(&mut self, frame: u64, key: usize, down: bool) {
+        self.events.push(KeyEvent {
+            frame,
+            key: key & 0xF,
+            down,
+        });
+        self.frames = self.frames.max(frame);
+    }
+
+    // Marks the end of the run
+    pub fn finish(&mut self, frame: u64) {
+        self.frames = self.frames.max(frame);
+    }
+
+    // Creates the processor and clock to replay the movie with
+    pub fn start(&self, rom: &[u8]) -> Result<(Proc, Clock)> {
+        if crc32(rom) != self.rom_checksum {
+            return Err(invalid("recorded with another rom"));
+        }
+        let mut proc = Proc::with_quirks(rom, self.quirks)?;
+        proc.set_seed(self.seed);
+        Ok((proc, Clock::new(self.instructions_per_second)))
+    }
+
+    pub fn replay(&self) -> Replay {
+        Replay {
+            events: self.events.clone(),
+            next: 0,
+            frames: self.frames,
+        }
+    }
+}
+
+fn invalid(reason: &str) -> ChipoError {
+    ChipoError::InvalidMovie(reason.to_string())
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "rom {:08X}", self.rom_checksum)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "quirks {}", self.quirks.bits())?;
+        writeln!(f, "ips {}", self.instructions_per_second)?;
+        writeln!(f, "frames {}", self.frames)?;
+        for event in &self.events {
+            let action = if event.down { "down" } else { "up" };
+            writeln!(f, "{} {} {:X}", event.frame, action, event.key)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Movie {
+    type Err = ChipoError;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.next() != Some(HEADER) {
+            return Err(invalid("not a chipo movie"));
+        }
+
+        let mut header = |name: &str| -> Result<String> {
+            match lines.next().and_then(|line| line.strip_prefix(name)) {
+                Some(value) => Ok(value.trim().to_string()),
+                None => Err(invalid(&format!("missing {}", name))),
+            }
+        };
+        let rom_checksum = u32::from_str_radix(&header("rom")?, 16)?;
+        let seed = header("seed")?.parse()?;
+        let quirks = Quirks::from_bits(header("quirks")?.parse()?);
+        let instructions_per_second = header("ips")?.parse()?;
+        let frames = header("frames")?.parse()?;
+
+        let events = lines
+            .map(|line| {
+                let fields = line.split_whitespace().collect::<Vec<&str>>();
+                let down = match fields.get(1) {
+                    Some(&"down") => true,
+                    Some(&"up") => false,
+                    _ => return Err(invalid(&format!("invalid event '{}'", line))),
+                };
+                let key = usize::from_str_radix(fields.get(2).unwrap_or(&""), 16)?;
+                Ok(KeyEvent {
+                    frame: fields[0].parse()?,
+                    key: key & 0xF,
+                    down,
+                })
+            })
+            .collect::<Result<Vec<KeyEvent>>>()?;
+
+        Ok(Movie {
+            rom_checksum,
+            seed,
+            quirks,
+            instructions_per_second,
+            frames,
+            events,
+        })
+    }
+}
+
+// Plays the key events of a movie back, frame by frame
+pub struct Replay {
+    events: Vec<KeyEvent>,
+    next: usize,
+    frames: u64,
+}
+
+impl Replay {
+    // Applies the events recorded before frame ran, to call before each frame
+    pub fn apply(&mut self, frame: u64, proc: &mut Proc) {
+        while let Some(event) = self.events.get(self.next) {
+            if event.frame > frame {
+                break;
+            }
+            proc.set_key(event.key, event.down);
+            self.next += 1;
+        }
+    }
+
+    pub fn is_finished(&self, frame: u64) -> bool {
+        frame >= self.frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+    use crate::emu::ProgramState;
+
+    // Runs frames, pressing keys at the given frames when recording
+    fn run(proc: &mut Proc, clock: &mut Clock, frames: u64, mut input: impl FnMut(u64, &mut Proc)) {
+        while clock.frame() < frames {
+            input(clock.frame(), proc);
+            for _ in 0..clock.next_frame() {
+                if let ProgramState::Stop = proc.cycle().unwrap() {
+                    return;
+                }
+            }
+            proc.decrement_registers();
+        }
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let rom = compile(
+            r#".code
+loop:
+    ld v0, k
+    rnd v1, 0xFF
+    add v2, v1
+    ld v3, 30
+    ld dt, v3
+wait:
+    ld v3, dt
+    se v3, 0
+    jp wait
+    jp loop"#,
+        )
+        .unwrap();
+
+        let mut proc = Proc::binary(&rom).unwrap();
+        let mut clock = Clock::new(700);
+        let mut movie = Movie::new(&rom, &proc, &clock);
+        run(&mut proc, &mut clock, 200, |frame, proc| {
+            for &(at, key, down) in &[
+                (10, 5, true),
+                (12, 5, false),
+                (60, 0xA, true),
+                (61, 0xA, false),
+            ] {
+                if at == frame {
+                    proc.set_key(key, down);
+                    movie.record(frame, key, down);
+                }
+            }
+        });
+        movie.finish(clock.frame());
+        let expected = proc.save_state();
+
+        let movie = movie.to_string().parse::<Movie>().unwrap();
+        assert_eq!(movie.events.len(), 4);
+        assert_eq!(movie.frames, 200);
+        let (mut proc, mut clock) = movie.start(&rom).unwrap();
+        let mut replay = movie.replay();
+        run(&mut proc, &mut clock, movie.frames, |frame, proc| {
+            replay.apply(frame, proc)
+        });
+        assert!(replay.is_finished(clock.frame()));
+        assert_eq!(proc.save_state(), expected);
+        assert_ne!(proc.registers()[2], 0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let rom = [0x00, 0xEE];
+        let proc = Proc::binary(&rom).unwrap();
+        let movie = Movie::new(&rom, &proc, &Clock::default());
+        assert!(movie.start(&[0x00, 0xE0]).is_err());
+        assert!("chipo-movie 1\nrom 0\n".parse::<Movie>().is_err());
+        assert!(format!("{}0 press 1\n", movie).parse::<Movie>().is_err());
+        assert!("nope".parse::<Movie>().is_err());
+    }
+}
@@ -1,29 +1,56 @@
-use rand::Rng;
-
-use crate::emu::{Addr, Instruction, Instruction::*, Keycode, Val};
+use crate::emu::state::{StateReader, StateWriter};
+use crate::emu::{Addr, Instruction, Instruction::*, Quirks, Random, Val, Watchpoint, XorShift};
 use crate::error::{ChipoError, Result};
 
+const FONT_ADDR: usize = 0x00;
+const BIG_FONT_ADDR: usize = 0x50;
+// XO-CHIP extends the address space to 64K
+const MEMORY_SIZE: usize = 0x10000;
+
 #[derive(Debug)]
 pub struct Proc {
-    memory: [Val; 4096],
+    memory: Vec<Val>,
     rg: [Val; 16],
+    flags: [Val; 16],
     i: Addr,
     delay_rg: Val,
     sound_rg: Val,
     pc: usize,
     stack: Vec<Addr>,
+    hires: bool,
+    quirks: Quirks,
+    plane: Val,
+    audio_pattern: Option<[Val; 16]>,
+    pitch: Val,
+    seed: u64,
+    rng: Box<dyn Random>,
+    watchpoints: Vec<Watchpoint>,
+    // Memory watchpoint hit by the instruction being executed
+    watch_hit: Option<Watchpoint>,
     pub should_render: bool,
-    pub pixels: [bool; 64 * 32],
+    // The buffer is sized for the SUPER-CHIP high resolution mode, only the
+    // first width() * height() pixels are used in low resolution.
+    // Each pixel holds one bit per XO-CHIP bitplane.
+    pub pixels: [Val; 128 * 64],
     keys: [bool; 16],
+    // Key pressed during FX0A, whose release completes it with the
+    // wait_for_release quirk
+    awaited_key: Option<usize>,
 }
 
 pub enum ProgramState {
     Continue,
     Stop,
+    // The instruction was executed and reached this watchpoint
+    Watchpoint(Watchpoint),
 }
 
 impl Proc {
     pub fn binary(blob: &[u8]) -> Result<Self> {
+        Self::with_quirks(blob, Quirks::default())
+    }
+
+    pub fn with_quirks(blob: &[u8], quirks: Quirks) -> Result<Self> {
         let fonts = vec![
             0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
             0x20, 0x60, 0x20, 0x20, 0x70, // 1
@@ -42,20 +69,53 @@ impl Proc {
             0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
             0xF0, 0x80, 0xF0, 0x80, 0x80, // F
         ];
+        let big_fonts = vec![
+            0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+            0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+            0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+            0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+            0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+            0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+            0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ];
+        let seed = rand::random();
         let mut proc = Proc {
-            memory: [0; 4096],
+            memory: vec![0; MEMORY_SIZE],
             rg: [0; 16],
+            flags: [0; 16],
             i: 0,
             delay_rg: 0,
             sound_rg: 0,
             pc: 0x200,
             stack: vec![],
+            hires: false,
+            quirks,
+            plane: 1,
+            audio_pattern: None,
+            pitch: 64,
+            seed,
+            rng: Box::new(XorShift::new(seed)),
+            watchpoints: vec![],
+            watch_hit: None,
             should_render: true,
-            pixels: [false; 64 * 32],
+            pixels: [0; 128 * 64],
             keys: [false; 16],
+            awaited_key: None,
         };
         for (pos, &b) in fonts.iter().enumerate() {
-            proc.memory[pos] = b;
+            proc.memory[FONT_ADDR + pos] = b;
+        }
+        for (pos, &b) in big_fonts.iter().enumerate() {
+            proc.memory[BIG_FONT_ADDR + pos] = b;
         }
         for (pos, &el) in blob.iter().enumerate() {
             proc.memory[0x200 + pos] = el;
@@ -63,14 +123,72 @@ impl Proc {
 
         Ok(proc)
     }
+    // Addresses wrap around the 64K of memory instead of going out of bounds
+    fn read(&self, addr: usize) -> Val {
+        self.memory[addr % MEMORY_SIZE]
+    }
+
+    fn word_at(&self, addr: usize) -> u16 {
+        ((self.read(addr) as u16) << 8) + (self.read(addr + 1) as u16)
+    }
+
+    // Skips the instruction at pc, which can be 4 bytes long in XO-CHIP
+    fn skip(&mut self) {
+        self.pc += if Instruction::is_long(self.word_at(self.pc)) {
+            4
+        } else {
+            2
+        };
+    }
+
+    // Decodes the instruction stored at addr, without executing it
+    pub fn instruction_at(&self, addr: usize) -> Result<Instruction> {
+        let instr = self.word_at(addr);
+        if Instruction::is_long(instr) {
+            Instruction::from_long(instr, self.word_at(addr + 2))
+        } else {
+            Instruction::from(instr)
+        }
+    }
+
+    // Writes to memory through here so that memory watchpoints see every write
+    fn write(&mut self, addr: usize, val: Val) {
+        let addr = addr % MEMORY_SIZE;
+        if self.watch_hit.is_none() {
+            self.watch_hit = self.watchpoints.iter().copied().find(|w| w.covers(addr));
+        }
+        self.memory[addr] = val;
+    }
+
     pub fn cycle(&mut self) -> Result<ProgramState> {
-        let instr = ((self.memory[self.pc] as u16) << 8) + (self.memory[self.pc + 1] as u16);
-        let for_instr = Instruction::from(instr)?;
+        if self.watchpoints.is_empty() {
+            return self.execute();
+        }
+        let (rg, i) = (self.rg, self.i);
+        let state = self.execute();
+        let hit = self.watch_hit.take().or_else(|| {
+            self.watchpoints
+                .iter()
+                .copied()
+                .find(|w| w.reached(&rg, i, &self.rg, self.i))
+        });
+        match (state, hit) {
+            (Ok(ProgramState::Continue), Some(watchpoint)) => {
+                Ok(ProgramState::Watchpoint(watchpoint))
+            }
+            (state, _) => state,
+        }
+    }
+
+    fn execute(&mut self) -> Result<ProgramState> {
+        let instr = self.word_at(self.pc);
+        let for_instr = self.instruction_at(self.pc)?;
 
         match for_instr {
             DisplayClear => {
+                let plane = self.plane;
                 self.pixels.iter_mut().for_each(|pixel| {
-                    *pixel = false;
+                    *pixel &= !plane;
                 });
                 self.pc += 2;
             }
@@ -89,28 +207,28 @@ impl Proc {
                 self.pc = addr as usize;
             }
             IfEq(vx, val) => {
+                self.pc += 2;
                 if self.rg[vx] == val {
-                    self.pc += 2;
+                    self.skip();
                 }
-                self.pc += 2;
             }
             IfEqRg(vx, vy) => {
+                self.pc += 2;
                 if self.rg[vx] == self.rg[vy] {
-                    self.pc += 2;
+                    self.skip();
                 }
-                self.pc += 2;
             }
             IfNeq(vx, val) => {
+                self.pc += 2;
                 if self.rg[vx] != val {
-                    self.pc += 2;
+                    self.skip();
                 }
-                self.pc += 2;
             }
             IfNeqRg(vx, vy) => {
+                self.pc += 2;
                 if self.rg[vx] != self.rg[vy] {
-                    self.pc += 2;
+                    self.skip();
                 }
-                self.pc += 2;
             }
             Set(vx, val) => {
                 self.rg[vx] = val;
@@ -132,14 +250,23 @@ impl Proc {
             }
             Or(vx, vy) => {
                 self.rg[vx] |= self.rg[vy];
+                if self.quirks.logic_resets_vf {
+                    self.rg[0xF] = 0;
+                }
                 self.pc += 2;
             }
             And(vx, vy) => {
                 self.rg[vx] &= self.rg[vy];
+                if self.quirks.logic_resets_vf {
+                    self.rg[0xF] = 0;
+                }
                 self.pc += 2;
             }
             Xor(vx, vy) => {
                 self.rg[vx] ^= self.rg[vy];
+                if self.quirks.logic_resets_vf {
+                    self.rg[0xF] = 0;
+                }
                 self.pc += 2;
             }
             Sub(vx, vy) => {
@@ -154,14 +281,16 @@ impl Proc {
                 self.rg[vx] = val;
                 self.pc += 2;
             }
-            RightShift(vx) => {
-                self.rg[0xF] = 0x01 & self.rg[vx];
-                self.rg[vx] >>= 1;
+            RightShift(vx, vy) => {
+                let val = self.shift_source(vx, vy);
+                self.rg[vx] = val >> 1;
+                self.rg[0xF] = 0x01 & val;
                 self.pc += 2;
             }
-            LeftShift(vx) => {
-                self.rg[0xF] = 0b1000_0000 & self.rg[vx];
-                self.rg[vx] <<= 1;
+            LeftShift(vx, vy) => {
+                let val = self.shift_source(vx, vy);
+                self.rg[vx] = val << 1;
+                self.rg[0xF] = (val >> 7) & 1;
                 self.pc += 2;
             }
             SetAddr(addr) => {
@@ -169,30 +298,56 @@ impl Proc {
                 self.pc += 2;
             }
             Jump(addr) => {
-                self.pc = self.rg[0] as usize + addr as usize;
+                let vx = if self.quirks.jump_uses_vx {
+                    (addr as usize >> 8) & 0xF
+                } else {
+                    0
+                };
+                self.pc = self.rg[vx] as usize + addr as usize;
             }
             Rand(vx, val) => {
-                let mut rng = rand::thread_rng();
-                let result = rng.gen_range(0, 255);
-                self.rg[vx] = result & val;
+                self.rg[vx] = self.rng.next_byte() & val;
                 self.pc += 2;
             }
             Disp(vx, vy, n) => {
                 self.rg[0xF] = 0x00;
-                for y in 0..n {
-                    let spr = self.memory[self.i as usize + y as usize];
-                    for x in 0..8 {
-                        if self.set_pixel(
-                            (self.rg[vx].wrapping_add(x)) as usize,
-                            (self.rg[vy].wrapping_add(y)) as usize,
-                            (spr >> (7 - x) & 0x01) != 0,
-                        ) {
+                let (x, y) = (self.rg[vx], self.rg[vy]);
+                let mut addr = self.i as usize;
+                // With both XO-CHIP planes selected, the sprite data for the
+                // second plane directly follows the one for the first plane.
+                for &plane in &[1, 2] {
+                    if self.plane & plane != 0 {
+                        if self.draw_sprite(addr, x, y, n, plane) {
                             self.rg[0xF] = 0x1;
                         }
+                        addr += if n == 0 { 32 } else { n as usize };
                     }
                 }
                 self.pc += 2;
             }
+            ScrollDown(n) => {
+                self.scroll(0, n as isize);
+                self.pc += 2;
+            }
+            ScrollRight => {
+                self.scroll(4, 0);
+                self.pc += 2;
+            }
+            ScrollLeft => {
+                self.scroll(-4, 0);
+                self.pc += 2;
+            }
+            Exit => {
+                return Ok(ProgramState::Stop);
+            }
+            LowRes => {
+                self.set_hires(false);
+                self.pc += 2;
+            }
+            HighRes => {
+                self.set_hires(true);
+                self.pc += 2;
+            }
             SetSoundTimer(vx) => {
                 self.sound_rg = self.rg[vx];
                 self.pc += 2;
@@ -206,20 +361,27 @@ impl Proc {
                 self.pc += 2;
             }
             BCD(vx) => {
-                self.memory[self.i as usize] = self.rg[vx] / 100;
-                self.memory[self.i as usize + 1] = (self.rg[vx] / 10) % 10;
-                self.memory[self.i as usize + 2] = (self.rg[vx] % 100) % 10;
+                let i = self.i as usize;
+                self.write(i, self.rg[vx] / 100);
+                self.write(i + 1, (self.rg[vx] / 10) % 10);
+                self.write(i + 2, (self.rg[vx] % 100) % 10);
                 self.pc += 2;
             }
             MemDump(vx) => {
                 for reg in 0..(vx + 1) {
-                    self.memory[self.i as usize + reg] = self.rg[reg];
+                    self.write(self.i as usize + reg, self.rg[reg]);
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(vx as Addr + 1) & 0xFFFF;
                 }
                 self.pc += 2;
             }
             MemLoad(vx) => {
                 for reg in 0..(vx + 1) {
-                    self.rg[reg] = self.memory[self.i as usize + reg];
+                    self.rg[reg] = self.read(self.i as usize + reg);
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(vx as Addr + 1) & 0xFFFF;
                 }
                 self.pc += 2;
             }
@@ -228,27 +390,81 @@ impl Proc {
                 self.pc += 2;
             }
             FontLoad(vx) => {
-                self.i = (self.rg[vx] * 5) as Addr;
+                self.i = (FONT_ADDR + (self.rg[vx] & 0xF) as usize * 5) as Addr;
+                self.pc += 2;
+            }
+            BigFontLoad(vx) => {
+                self.i = (BIG_FONT_ADDR + (self.rg[vx] & 0xF) as usize * 10) as Addr;
+                self.pc += 2;
+            }
+            SaveFlags(vx) => {
+                self.flags[..=vx].copy_from_slice(&self.rg[..=vx]);
+                self.pc += 2;
+            }
+            LoadFlags(vx) => {
+                self.rg[..=vx].copy_from_slice(&self.flags[..=vx]);
+                self.pc += 2;
+            }
+            LongSetAddr(addr) => {
+                self.i = addr;
+                self.pc += 4;
+            }
+            SaveRange(vx, vy) => {
+                for (offset, reg) in register_range(vx, vy).enumerate() {
+                    self.write(self.i as usize + offset, self.rg[reg]);
+                }
+                self.pc += 2;
+            }
+            LoadRange(vx, vy) => {
+                for (offset, reg) in register_range(vx, vy).enumerate() {
+                    self.rg[reg] = self.read(self.i as usize + offset);
+                }
+                self.pc += 2;
+            }
+            SelectPlane(mask) => {
+                self.plane = mask & 0x3;
+                self.pc += 2;
+            }
+            LoadAudio => {
+                let mut pattern = [0; 16];
+                let start = self.i as usize;
+                for (offset, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.read(start + offset);
+                }
+                self.audio_pattern = Some(pattern);
+                self.pc += 2;
+            }
+            SetPitch(vx) => {
+                self.pitch = self.rg[vx];
                 self.pc += 2;
             }
             KeyOpEq(vx) => {
-                let key = self.keys.iter().position(|&b| b);
-                if Some(self.rg[vx] as usize) == key {
-                    self.pc += 4;
-                } else {
-                    self.pc += 2;
+                self.pc += 2;
+                if self.keys[self.rg[vx] as usize & 0xF] {
+                    self.skip();
                 }
             }
             KeyOpNeq(vx) => {
-                let key = self.keys.iter().position(|&b| b);
-                if Some(self.rg[vx] as usize) != key {
-                    self.pc += 4;
-                } else {
-                    self.pc += 2;
+                self.pc += 2;
+                if !self.keys[self.rg[vx] as usize & 0xF] {
+                    self.skip();
                 }
             }
             GetKeyOp(vx) => {
-                let key = self.keys.iter().position(|&b| b);
+                let pressed = self.keys.iter().position(|&b| b);
+                let key = if self.quirks.wait_for_release {
+                    // The first key pressed is kept until it is released
+                    match self.awaited_key {
+                        Some(k) if !self.keys[k] => self.awaited_key.take(),
+                        Some(_) => None,
+                        None => {
+                            self.awaited_key = pressed;
+                            None
+                        }
+                    }
+                } else {
+                    pressed
+                };
                 if let Some(k) = key {
                     self.rg[vx] = k as Val;
                     self.pc += 2;
@@ -271,48 +487,357 @@ impl Proc {
         }
     }
 
-    pub fn set_pixel(&mut self, x: usize, y: usize, val: bool) -> bool {
+    pub fn width(&self) -> usize {
+        if self.hires {
+            128
+        } else {
+            64
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            64
+        } else {
+            32
+        }
+    }
+
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixels.iter_mut().for_each(|pixel| {
+            *pixel = 0;
+        });
         self.should_render = true;
-        let location = (x % 64) + 64 * (y % 32);
-        let collision = self.pixels[location] && val;
-        self.pixels[location] ^= val;
-        collision
     }
-    pub fn set_key_down(&mut self, keycode: Keycode) {
-        if let Some(i) = self.get_key_index(keycode) {
-            self.keys[i] = true;
+
+    // VY when the shift quirk is enabled, VX otherwise
+    fn shift_source(&self, vx: usize, vy: usize) -> Val {
+        if self.quirks.shift_uses_vy {
+            self.rg[vy]
+        } else {
+            self.rg[vx]
         }
     }
-    pub fn set_key_up(&mut self, keycode: Keycode) {
-        if let Some(i) = self.get_key_index(keycode) {
-            self.keys[i] = false;
+
+    // Draws an 8xN sprite, or a 16x16 one when n is 0, on the given plane
+    fn draw_sprite(&mut self, addr: usize, x: Val, y: Val, n: Val, plane: Val) -> bool {
+        let (rows, cols) = if n == 0 { (16, 16) } else { (n as usize, 8) };
+        // The starting position always wraps, only the sprite itself is clipped
+        let (x, y) = (x as usize % self.width(), y as usize % self.height());
+        let mut collision = false;
+        for row in 0..rows {
+            if self.quirks.clip_sprites && y + row >= self.height() {
+                break;
+            }
+            let spr = if n == 0 {
+                self.word_at(addr + 2 * row)
+            } else {
+                (self.read(addr + row) as u16) << 8
+            };
+            for col in 0..cols {
+                if self.quirks.clip_sprites && x + col >= self.width() {
+                    break;
+                }
+                if self.set_pixel(x + col, y + row, plane, (spr >> (15 - col) & 0x01) != 0) {
+                    collision = true;
+                }
+            }
         }
+        collision
     }
-    fn get_key_index(&self, keycode: Keycode) -> Option<usize> {
-        // From http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#keyboard
-        match keycode {
-            Keycode::Num1 => Some(1),
-            Keycode::Num2 => Some(2),
-            Keycode::Num3 => Some(3),
-            Keycode::Num4 => Some(0xC),
-            Keycode::Q => Some(4),
-            Keycode::W => Some(5),
-            Keycode::E => Some(6),
-            Keycode::R => Some(0xD),
-            Keycode::A => Some(7),
-            Keycode::S => Some(8),
-            Keycode::D => Some(9),
-            Keycode::F => Some(0xE),
-            Keycode::Z => Some(0xA),
-            Keycode::X => Some(0),
-            Keycode::C => Some(0xB),
-            Keycode::V => Some(0xF),
-            _ => None,
+
+    // Moves the selected planes by (dx, dy) pixels, filling the gaps with blanks
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let plane = self.plane;
+        let previous = self.pixels;
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let src = if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    previous[(src_x + width * src_y) as usize]
+                } else {
+                    0
+                };
+                let pixel = &mut self.pixels[(x + width * y) as usize];
+                *pixel = (*pixel & !plane) | (src & plane);
+            }
         }
+        self.should_render = true;
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, plane: Val, val: bool) -> bool {
+        self.should_render = true;
+        let width = self.width();
+        let location = (x % width) + width * (y % self.height());
+        let collision = self.pixels[location] & plane != 0 && val;
+        if val {
+            self.pixels[location] ^= plane;
+        }
+        collision
+    }
+    // Keys are given by their hexadecimal index on the keypad, frontends map
+    // the host keys with a Keymap
+    pub fn set_key_down(&mut self, key: usize) {
+        self.set_key(key, true);
+    }
+    pub fn set_key_up(&mut self, key: usize) {
+        self.set_key(key, false);
+    }
+    pub fn set_key(&mut self, key: usize, down: bool) {
+        self.keys[key & 0xF] = down;
     }
     pub fn should_buzz(&self) -> bool {
         self.sound_rg > 0
     }
+
+    // The XO-CHIP 128 bits audio pattern, None when the program never loaded one
+    pub fn audio_pattern(&self) -> Option<&[Val; 16]> {
+        self.audio_pattern.as_ref()
+    }
+
+    // Playback rate of the audio pattern in bits per second
+    pub fn audio_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    pub fn registers(&self) -> &[Val; 16] {
+        &self.rg
+    }
+
+    pub fn i(&self) -> Addr {
+        self.i
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn delay_timer(&self) -> Val {
+        self.delay_rg
+    }
+
+    pub fn sound_timer(&self) -> Val {
+        self.sound_rg
+    }
+
+    pub fn stack(&self) -> &[Addr] {
+        &self.stack
+    }
+
+    pub fn memory(&self) -> &[Val] {
+        &self.memory
+    }
+
+    pub fn set_register(&mut self, vx: usize, val: Val) {
+        self.rg[vx] = val;
+    }
+
+    pub fn set_i(&mut self, i: Addr) {
+        self.i = i;
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn set_delay_timer(&mut self, val: Val) {
+        self.delay_rg = val;
+    }
+
+    pub fn set_sound_timer(&mut self, val: Val) {
+        self.sound_rg = val;
+    }
+
+    // Writes bytes from a debugger, without triggering watchpoints
+    pub fn write_memory(&mut self, addr: usize, bytes: &[Val]) -> Result<()> {
+        let end = addr
+            .checked_add(bytes.len())
+            .ok_or(ChipoError::InvalidAddress(addr))?;
+        match self.memory.get_mut(addr..end) {
+            Some(memory) => {
+                memory.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(ChipoError::InvalidAddress(addr)),
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Restarts the random generator from seed, making CXNN reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng.set_state(seed);
+    }
+
+    // Replaces the random generator, which is then reseeded with the current seed
+    pub fn set_random(&mut self, rng: Box<dyn Random>) {
+        self.rng = rng;
+        self.rng.set_state(self.seed);
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.bytes(&self.memory);
+        state.bytes(&self.rg);
+        state.bytes(&self.flags);
+        state.u32(self.i);
+        state.u8(self.delay_rg);
+        state.u8(self.sound_rg);
+        state.u32(self.pc as u32);
+        state.u16(self.stack.len() as u16);
+        for &addr in &self.stack {
+            state.u32(addr);
+        }
+        state.u8(self.hires as u8);
+        state.u8(self.quirks.bits());
+        state.u8(self.plane);
+        match &self.audio_pattern {
+            Some(pattern) => {
+                state.u8(1);
+                state.bytes(pattern);
+            }
+            None => state.u8(0),
+        }
+        state.u8(self.pitch);
+        state.u64(self.seed);
+        state.u64(self.rng.state());
+        state.bytes(&self.pixels);
+        state.u16(
+            self.keys
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (i, &down)| acc | (down as u16) << i),
+        );
+        state.u8(self.awaited_key.map_or(0xFF, |k| k as u8));
+        state.finish()
+    }
+
+    // Restores a state from save_state, the processor is left untouched on error
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut state = StateReader::new(state)?;
+        let memory = state.bytes(MEMORY_SIZE)?;
+        let rg = state.bytes(16)?;
+        let flags = state.bytes(16)?;
+        let i = state.u32()?;
+        let delay_rg = state.u8()?;
+        let sound_rg = state.u8()?;
+        let pc = state.u32()? as usize;
+        let stack = (0..state.u16()?)
+            .map(|_| state.u32())
+            .collect::<Result<Vec<Addr>>>()?;
+        let hires = state.u8()? != 0;
+        let quirks = state.u8()?;
+        let plane = state.u8()?;
+        let audio_pattern = match state.u8()? {
+            0 => None,
+            _ => {
+                let mut pattern = [0; 16];
+                pattern.copy_from_slice(state.bytes(16)?);
+                Some(pattern)
+            }
+        };
+        let pitch = state.u8()?;
+        let rng = if state.version() >= 2 {
+            Some((state.u64()?, state.u64()?))
+        } else {
+            None
+        };
+        let pixels = state.bytes(128 * 64)?;
+        let keys = state.u16()?;
+        let awaited_key = match state.version() {
+            0..=2 => None,
+            _ => match state.u8()? {
+                0xFF => None,
+                key => Some(key as usize & 0xF),
+            },
+        };
+        state.finish()?;
+        // An instruction must fit in memory wherever pc or a return address points
+        if pc >= MEMORY_SIZE - 1 {
+            return Err(ChipoError::InvalidState(format!(
+                "pc {:#X} out of memory",
+                pc
+            )));
+        }
+        if let Some(addr) = stack.iter().find(|&&addr| addr as usize >= MEMORY_SIZE - 1) {
+            return Err(ChipoError::InvalidState(format!(
+                "return address {:#X} out of memory",
+                addr
+            )));
+        }
+        if i > 0xFFFF {
+            return Err(ChipoError::InvalidState(format!(
+                "index {:#X} out of memory",
+                i
+            )));
+        }
+        if plane > 0x3 {
+            return Err(ChipoError::InvalidState(format!("invalid plane {}", plane)));
+        }
+
+        self.memory.copy_from_slice(memory);
+        self.rg.copy_from_slice(rg);
+        self.flags.copy_from_slice(flags);
+        self.i = i;
+        self.delay_rg = delay_rg;
+        self.sound_rg = sound_rg;
+        self.pc = pc;
+        self.stack = stack;
+        self.hires = hires;
+        self.quirks = Quirks::from_bits(quirks);
+        self.plane = plane;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        if let Some((seed, rng_state)) = rng {
+            self.seed = seed;
+            self.rng.set_state(rng_state);
+        }
+        self.pixels.copy_from_slice(pixels);
+        for (i, key) in self.keys.iter_mut().enumerate() {
+            *key = keys & 1 << i != 0;
+        }
+        self.awaited_key = awaited_key;
+        self.should_render = true;
+        Ok(())
+    }
+}
+
+// Registers from vx to vy included, in reverse order when vx > vy
+fn register_range(vx: usize, vy: usize) -> Box<dyn Iterator<Item = usize>> {
+    if vx <= vy {
+        Box::new(vx..=vy)
+    } else {
+        Box::new((vy..=vx).rev())
+    }
 }
 
 #[cfg(test)]
@@ -321,8 +846,12 @@ mod tests {
     use crate::compile;
 
     fn exec(prg: &str) -> Proc {
+        exec_with_quirks(prg, Quirks::default())
+    }
+
+    fn exec_with_quirks(prg: &str, quirks: Quirks) -> Proc {
         let binary = compile(&format!(".code\n{}\nret", prg)).unwrap();
-        let mut proc = Proc::binary(&binary).unwrap();
+        let mut proc = Proc::with_quirks(&binary, quirks).unwrap();
 
         while let Ok(ProgramState::Continue) = proc.cycle() {}
 
@@ -514,4 +1043,577 @@ addr:
         assert_eq!(proc.rg[1], 251);
         assert_eq!(proc.rg[0xF], 1);
     }
+
+    #[test]
+    fn test_hires() {
+        let proc = exec(
+            r#"
+    high
+    ld v0, 120
+    ld v1, 60
+    ld v2, 0
+    ld f, v2
+    drw v0, v1, 5
+    "#,
+        );
+
+        assert_eq!(proc.width(), 128);
+        assert_eq!(proc.height(), 64);
+        assert_eq!(proc.pixels[120 + 128 * 60], 1);
+
+        let proc = exec(
+            r#"
+    high
+    low
+    "#,
+        );
+
+        assert_eq!(proc.width(), 64);
+        assert_eq!(proc.height(), 32);
+    }
+
+    #[test]
+    fn test_big_sprite() {
+        let proc = exec(
+            r#"
+    ld v0, 0
+    ld v1, 8
+    ld hf, v1
+    drw v0, v0, 0
+    drw v0, v0, 0
+    "#,
+        );
+
+        assert_eq!(proc.i, 0x50 + 8 * 10);
+        assert_eq!(proc.rg[0xF], 1);
+        assert!(proc.pixels.iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn test_scroll() {
+        let proc = exec(
+            r#"
+    ld v0, 0
+    ld v1, 8
+    ld f, v1
+    drw v0, v0, 1
+    scd 2
+    scr
+    "#,
+        );
+
+        // The top row of the 8 glyph is 0xF0
+        for x in 0..8 {
+            assert_eq!(proc.pixels[x + 64 * 2], (x >= 4) as u8);
+        }
+        assert!(proc.pixels[..64 * 2].iter().all(|&p| p == 0));
+
+        let proc = exec(
+            r#"
+    ld v0, 4
+    ld v1, 8
+    ld f, v1
+    drw v0, v0, 1
+    scl
+    "#,
+        );
+
+        assert!(proc.pixels[4 * 64..4 * 64 + 4].iter().all(|&p| p == 1));
+        assert_eq!(proc.pixels[4 * 64 + 4], 0);
+    }
+
+    #[test]
+    fn test_flags() {
+        let proc = exec(
+            r#"
+    ld v0, 1
+    ld v1, 2
+    ld r, v1
+    ld v0, 0
+    ld v1, 0
+    ld v1, r
+    "#,
+        );
+
+        assert_eq!(proc.rg[0], 1);
+        assert_eq!(proc.rg[1], 2);
+    }
+
+    #[test]
+    fn test_exit() {
+        let proc = exec(
+            r#"
+    ld v0, 1
+    exit
+    ld v0, 2
+    "#,
+        );
+
+        assert_eq!(proc.rg[0], 1);
+    }
+
+    #[test]
+    fn test_long_set_addr() {
+        let proc = exec(
+            r#"
+    ld i, long 0xABCD
+    "#,
+        );
+
+        assert_eq!(proc.i, 0xABCD);
+
+        let proc = exec(
+            r#"
+    ld v0, 1
+    se v0, 1
+    ld i, long 0x1234
+    ld v1, 2
+    "#,
+        );
+
+        assert_eq!(proc.i, 0);
+        assert_eq!(proc.rg[1], 2);
+
+        // No key is pressed, so only sknp skips the long instruction
+        let proc = exec(
+            r#"
+    ld v0, 1
+    sknp v0
+    ld i, long 0x1234
+    skp v0
+    ld i, long 0x5678
+    ld v1, 2
+    "#,
+        );
+
+        assert_eq!(proc.i, 0x5678);
+        assert_eq!(proc.rg[1], 2);
+    }
+
+    #[test]
+    fn test_save_load_range() {
+        let proc = exec(
+            r#"
+    ld i, long 0x8000
+    ld v2, 2
+    ld v3, 3
+    ld v4, 4
+    save v2, v4
+    load v7, v5
+    "#,
+        );
+
+        assert_eq!(proc.memory[0x8000..0x8003], [2, 3, 4]);
+        assert_eq!(proc.rg[7], 2);
+        assert_eq!(proc.rg[6], 3);
+        assert_eq!(proc.rg[5], 4);
+        assert_eq!(proc.i, 0x8000);
+    }
+
+    #[test]
+    fn test_memory_wraps() {
+        let proc = exec(
+            r#"
+    ld i, long 0xFFFE
+    ld v0, 1
+    ld v1, 2
+    ld v2, 3
+    save v0, v2
+    load v3, v5
+    audio
+    "#,
+        );
+
+        assert_eq!(proc.memory[0xFFFE..], [1, 2]);
+        assert_eq!(proc.memory[0], 3);
+        assert_eq!(proc.rg[3..6], [1, 2, 3]);
+        assert_eq!(proc.audio_pattern().unwrap()[..3], [1, 2, 3]);
+
+        let mut proc = Proc::binary(&[]).unwrap();
+        proc.pc = 0xFFFF;
+        assert!(proc.instruction_at(proc.pc).is_ok());
+    }
+
+    #[test]
+    fn test_planes() {
+        let proc = exec(
+            r#"
+    ld v0, 0
+    ld v1, 8
+    ld f, v1
+    plane 2
+    drw v0, v0, 1
+    plane 3
+    drw v0, v0, 1
+    "#,
+        );
+
+        // The second drw reads 0xF0 for plane 1 and the next row of the
+        // 8 glyph, 0x90, for plane 2.
+        assert_eq!(proc.rg[0xF], 1);
+        assert_eq!(proc.pixels[..4], [1, 3, 3, 1]);
+
+        let proc = exec(
+            r#"
+    ld v0, 0
+    ld v1, 8
+    ld f, v1
+    plane 3
+    drw v0, v0, 1
+    plane 1
+    cls
+    "#,
+        );
+
+        assert_eq!(proc.pixels[..4], [2, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_audio() {
+        let proc = exec(
+            r#"
+    ld v0, 112
+    ld pitch, v0
+    ld i, 0
+    audio
+    "#,
+        );
+
+        assert_eq!(proc.audio_rate(), 8000.0);
+        assert_eq!(
+            proc.audio_pattern().unwrap()[..5],
+            [0xF0, 0x90, 0x90, 0x90, 0xF0]
+        );
+    }
+
+    #[test]
+    fn test_shift_quirk() {
+        let prg = r#"
+    ld v0, 1
+    ld v1, 0x81
+    shr v0, v1
+    "#;
+
+        let proc = exec(prg);
+        assert_eq!(proc.rg[0], 0);
+        assert_eq!(proc.rg[0xF], 1);
+
+        let proc = exec_with_quirks(prg, Quirks::COSMAC_VIP);
+        assert_eq!(proc.rg[0], 0x40);
+        assert_eq!(proc.rg[0xF], 1);
+
+        let prg = r#"
+    ld v0, 0x81
+    ld v1, 0x41
+    shl v0, v1
+    "#;
+
+        let proc = exec(prg);
+        assert_eq!(proc.rg[0], 0x02);
+        assert_eq!(proc.rg[0xF], 1);
+
+        let proc = exec_with_quirks(prg, Quirks::COSMAC_VIP);
+        assert_eq!(proc.rg[0], 0x82);
+        assert_eq!(proc.rg[0xF], 0);
+    }
+
+    #[test]
+    fn test_load_store_quirk() {
+        let prg = r#"
+    ld i, 0x300
+    ld v0, 1
+    ld v1, 2
+    ld [i], v1
+    "#;
+
+        assert_eq!(exec(prg).i, 0x300);
+        assert_eq!(exec_with_quirks(prg, Quirks::COSMAC_VIP).i, 0x302);
+
+        let prg = r#"
+    ld i, long 0xFFFF
+    ld [i], v1
+    "#;
+        assert_eq!(exec_with_quirks(prg, Quirks::COSMAC_VIP).i, 0x1);
+    }
+
+    #[test]
+    fn test_jump_quirk() {
+        // jp v0, 0x20A skips the ret when using v0 and lands after it using v2
+        let prg = r#"
+    ld v0, 0
+    ld v2, 2
+    jp v0, 0x20A
+    ld v3, 1
+    ret
+    ld v3, 2
+    "#;
+
+        assert_eq!(exec(prg).rg[3], 2);
+        assert_eq!(exec_with_quirks(prg, Quirks::SUPER_CHIP).rg[3], 0);
+    }
+
+    #[test]
+    fn test_clip_quirk() {
+        let prg = r#"
+    ld v0, 62
+    ld v1, 0
+    ld f, v1
+    drw v0, v1, 1
+    "#;
+
+        let proc = exec(prg);
+        assert_eq!(proc.pixels[..2], [1, 1]);
+        assert_eq!(proc.pixels[62..64], [1, 1]);
+
+        let proc = exec_with_quirks(prg, Quirks::COSMAC_VIP);
+        assert_eq!(proc.pixels[..2], [0, 0]);
+        assert_eq!(proc.pixels[62..64], [1, 1]);
+    }
+
+    #[test]
+    fn test_vf_reset_quirk() {
+        let prg = r#"
+    ld vf, 1
+    ld v0, 1
+    or v0, v0
+    "#;
+
+        assert_eq!(exec(prg).rg[0xF], 1);
+        assert_eq!(exec_with_quirks(prg, Quirks::COSMAC_VIP).rg[0xF], 0);
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let mut proc = exec(
+            r#"
+    ld v0, 42
+    ld i, 0x300
+    ld [i], v0
+    ld v1, 0
+    ld f, v1
+    drw v1, v1, 5
+    call sub
+    ret
+sub:
+    ld v2, 1
+    "#,
+        );
+        let state = proc.save_state();
+
+        let mut other = Proc::binary(&[]).unwrap();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.rg, proc.rg);
+        assert_eq!(other.i, proc.i);
+        assert_eq!(other.memory, proc.memory);
+        assert_eq!(other.pixels[..], proc.pixels[..]);
+        assert_eq!(other.stack, proc.stack);
+        assert_eq!(other.save_state(), state);
+
+        let mut corrupted = state.clone();
+        corrupted[0x300] ^= 1;
+        proc.rg[0] = 0;
+        assert!(proc.load_state(&corrupted).is_err());
+        assert_eq!(proc.rg[0], 0);
+
+        let mut bad = Proc::binary(&[]).unwrap();
+        bad.pc = 0xFFFF;
+        assert!(proc.load_state(&bad.save_state()).is_err());
+        bad.pc = 0x200;
+        bad.stack.push(0x10000);
+        assert!(proc.load_state(&bad.save_state()).is_err());
+        bad.stack.clear();
+        bad.plane = 4;
+        assert!(proc.load_state(&bad.save_state()).is_err());
+        bad.plane = 1;
+        bad.i = u32::MAX;
+        assert!(proc.load_state(&bad.save_state()).is_err());
+        bad.i = 0;
+        bad.plane = 1;
+        assert!(proc.load_state(&bad.save_state()).is_ok());
+    }
+
+    #[test]
+    fn test_seeded_rand() {
+        let binary = compile(
+            r#".code
+    rnd v0, 0xFF
+    rnd v1, 0xFF
+    rnd v2, 0x0F
+    ret"#,
+        )
+        .unwrap();
+        let run = |seed| {
+            let mut proc = Proc::binary(&binary).unwrap();
+            proc.set_seed(seed);
+            while let Ok(ProgramState::Continue) = proc.cycle() {}
+            proc.rg
+        };
+
+        assert_eq!(run(1234), run(1234));
+        assert_ne!(run(1234), run(4321));
+        assert!(run(1234)[2] <= 0x0F);
+    }
+
+    #[test]
+    fn test_state_keeps_rng() {
+        let binary = compile(
+            r#".code
+    rnd v0, 0xFF
+    rnd v1, 0xFF
+    ret"#,
+        )
+        .unwrap();
+        let mut proc = Proc::binary(&binary).unwrap();
+        proc.set_seed(99);
+        proc.cycle().unwrap();
+        let state = proc.save_state();
+        proc.cycle().unwrap();
+        let expected = proc.rg[1];
+
+        let mut other = Proc::binary(&binary).unwrap();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.seed(), 99);
+        other.cycle().unwrap();
+        assert_eq!(other.rg[1], expected);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let binary = compile(
+            r#".code
+    ld v0, 125
+    ld i, 0x300
+    ld b, v0
+    ld v1, 1
+    add v1, 1
+    ld [i], v1
+    ret"#,
+        )
+        .unwrap();
+        let mut proc = Proc::binary(&binary).unwrap();
+        proc.add_watchpoint(Watchpoint::Index(0x300));
+        proc.add_watchpoint(Watchpoint::memory(0x302, 1));
+        proc.add_watchpoint(Watchpoint::Register(1, 2));
+        proc.add_watchpoint(Watchpoint::memory(0x301, 1));
+
+        let mut hits = vec![];
+        loop {
+            match proc.cycle().unwrap() {
+                ProgramState::Continue => {}
+                ProgramState::Stop => break,
+                ProgramState::Watchpoint(watchpoint) => hits.push((proc.pc, watchpoint)),
+            }
+        }
+        assert_eq!(
+            hits,
+            vec![
+                (0x204, Watchpoint::Index(0x300)),
+                // The first watched address written by ld b is reported
+                (0x206, Watchpoint::memory(0x301, 1)),
+                (0x20A, Watchpoint::Register(1, 2)),
+                (0x20C, Watchpoint::memory(0x301, 1)),
+            ]
+        );
+        assert_eq!(proc.memory[0x300..0x303], [125, 2, 5]);
+
+        let watchpoint = Watchpoint::memory(usize::MAX - 1, 4);
+        assert!(watchpoint.covers(usize::MAX - 1));
+    }
+
+    #[test]
+    fn test_remove_watchpoint() {
+        let mut proc = Proc::binary(&compile(".code\n    ld v0, 1\n    ret").unwrap()).unwrap();
+        proc.add_watchpoint(Watchpoint::Register(0, 1));
+        proc.add_watchpoint(Watchpoint::Register(0, 1));
+        assert_eq!(proc.watchpoints().len(), 1);
+        assert!(proc.remove_watchpoint(&Watchpoint::Register(0, 1)));
+        assert!(!proc.remove_watchpoint(&Watchpoint::Register(0, 1)));
+        assert!(matches!(proc.cycle().unwrap(), ProgramState::Continue));
+    }
+
+    #[test]
+    fn test_simultaneous_keys() {
+        let binary = compile(
+            r#".code
+    ld v0, 3
+    ld v1, 9
+    ld v2, 0
+    ld v3, 0
+    skp v0
+    add v2, 1
+    skp v1
+    add v2, 2
+    sknp v1
+    add v3, 1
+    ret"#,
+        )
+        .unwrap();
+        let mut proc = Proc::binary(&binary).unwrap();
+        proc.set_key_down(3);
+        proc.set_key_down(9);
+        while let Ok(ProgramState::Continue) = proc.cycle() {}
+        // Both held keys are seen, not only the lowest one
+        assert_eq!((proc.rg[2], proc.rg[3]), (0, 1));
+
+        let mut proc = Proc::binary(&binary).unwrap();
+        proc.set_key_down(1);
+        proc.set_key_down(3);
+        while let Ok(ProgramState::Continue) = proc.cycle() {}
+        assert_eq!((proc.rg[2], proc.rg[3]), (2, 0));
+
+        // A held key skips a whole long instruction
+        let binary = compile(
+            r#".code
+    ld v0, 3
+    ld v1, 9
+    skp v0
+    ld i, long 0x1234
+    sknp v1
+    ld i, long 0x5678
+    ret"#,
+        )
+        .unwrap();
+        let mut proc = Proc::binary(&binary).unwrap();
+        proc.set_key_down(3);
+        proc.set_key_down(9);
+        while let Ok(ProgramState::Continue) = proc.cycle() {}
+        assert_eq!(proc.i, 0x5678);
+    }
+
+    #[test]
+    fn test_wait_for_release_quirk() {
+        let binary = compile(
+            ".code
+    ld v0, k
+    ret",
+        )
+        .unwrap();
+        let quirks = Quirks {
+            wait_for_release: true,
+            ..Quirks::default()
+        };
+
+        let mut proc = Proc::with_quirks(&binary, quirks).unwrap();
+        proc.set_key_down(0xB);
+        proc.set_key_down(4);
+        proc.cycle().unwrap();
+        proc.cycle().unwrap();
+        assert_eq!(proc.pc, 0x200);
+        // Releasing another key doesn't complete the wait
+        proc.set_key_up(0xB);
+        proc.cycle().unwrap();
+        assert_eq!(proc.pc, 0x200);
+
+        // The state is saved while waiting for the release
+        let mut other = Proc::with_quirks(&binary, quirks).unwrap();
+        other.load_state(&proc.save_state()).unwrap();
+        other.set_key_up(4);
+        other.cycle().unwrap();
+        assert_eq!((other.pc, other.rg[0]), (0x202, 4));
+
+        let mut proc = Proc::binary(&binary).unwrap();
+        proc.set_key_down(7);
+        proc.cycle().unwrap();
+        assert_eq!((proc.pc, proc.rg[0]), (0x202, 7));
+    }
 }
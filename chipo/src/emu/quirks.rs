@@ -0,0 +1,95 @@
+use std::str::FromStr;
+
+use crate::error::ChipoError;
+
+// Behaviours of the ambiguous opcodes, which differ between interpreters.
+// The default is the historical chipo behaviour.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    // FX55 and FX65 leave I pointing after the last register
+    pub load_store_increments_i: bool,
+    // BXNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    // 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // FX0A waits for a key to be pressed then released instead of pressed
+    pub wait_for_release: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        clip_sprites: true,
+        logic_resets_vf: true,
+        wait_for_release: true,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: true,
+        jump_uses_vx: true,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        wait_for_release: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: true,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        wait_for_release: false,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        clip_sprites: false,
+        logic_resets_vf: false,
+        wait_for_release: true,
+    };
+
+    // Packs the quirks in a byte, for save states and movies
+    pub fn bits(&self) -> u8 {
+        self.shift_uses_vy as u8
+            | (self.load_store_increments_i as u8) << 1
+            | (self.jump_uses_vx as u8) << 2
+            | (self.clip_sprites as u8) << 3
+            | (self.logic_resets_vf as u8) << 4
+            | (self.wait_for_release as u8) << 5
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Quirks {
+            shift_uses_vy: bits & 1 != 0,
+            load_store_increments_i: bits & 1 << 1 != 0,
+            jump_uses_vx: bits & 1 << 2 != 0,
+            clip_sprites: bits & 1 << 3 != 0,
+            logic_resets_vf: bits & 1 << 4 != 0,
+            wait_for_release: bits & 1 << 5 != 0,
+        }
+    }
+}
+
+impl FromStr for Quirks {
+    type Err = ChipoError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "default" => Ok(Quirks::default()),
+            "vip" | "cosmac-vip" => Ok(Quirks::COSMAC_VIP),
+            "chip-48" => Ok(Quirks::CHIP_48),
+            "schip" | "super-chip" => Ok(Quirks::SUPER_CHIP),
+            "xo-chip" => Ok(Quirks::XO_CHIP),
+            _ => Err(ChipoError::UnknownQuirks(name.to_string())),
+        }
+    }
+}
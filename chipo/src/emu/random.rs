@@ -0,0 +1,69 @@
+use std::fmt::Debug;
+
+// Source of the CXNN random bytes. The state is saved with the processor so
+// that a run can be reproduced from a save state.
+pub trait Random: Debug {
+    fn next_byte(&mut self) -> u8;
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+// xorshift64* generator, small and good enough for games
+#[derive(Debug, Clone)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = XorShift { state: 0 };
+        rng.set_state(seed);
+        rng
+    }
+}
+
+impl Random for XorShift {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        // The generator would only ever produce zeroes from a null state
+        self.state = if state == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            state
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded() {
+        let mut a = XorShift::new(42);
+        let mut b = XorShift::new(42);
+        let bytes: Vec<u8> = (0..100).map(|_| a.next_byte()).collect();
+        assert_eq!(bytes, (0..100).map(|_| b.next_byte()).collect::<Vec<u8>>());
+        assert_ne!(XorShift::new(43).next_byte(), bytes[0]);
+    }
+
+    #[test]
+    fn test_full_range() {
+        let mut rng = XorShift::new(0);
+        let mut seen = [false; 256];
+        for _ in 0..10_000 {
+            seen[rng.next_byte() as usize] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+
+use crate::emu::Proc;
+use crate::error::Result;
+
+// Bounded history of save states used to step back in time. Only the latest
+// state is kept whole, each older frame is stored as the compressed XOR with
+// the frame that follows it, which is mostly zeroes between two frames.
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Self {
+        Rewind {
+            capacity,
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // Number of frames that can be rewound
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    // Records the current state of the processor as the latest frame
+    pub fn push(&mut self, proc: &Proc) {
+        let state = proc.save_state();
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(compress(&state, &previous));
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    // Moves up to frames back in the history and loads that state in proc,
+    // returns the number of frames actually rewound
+    pub fn rewind(&mut self, proc: &mut Proc, frames: usize) -> Result<usize> {
+        let latest = match self.latest.as_ref() {
+            Some(state) => state,
+            None => return Ok(0),
+        };
+        let rewound = frames.min(self.deltas.len());
+        let state = self
+            .deltas
+            .iter()
+            .rev()
+            .take(rewound)
+            .fold(latest.clone(), |state, delta| decompress(&state, delta));
+        // The history is only dropped once the older state has been loaded
+        proc.load_state(&state)?;
+        self.deltas.truncate(self.deltas.len() - rewound);
+        self.latest = Some(state);
+        Ok(rewound)
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+// Encodes target XOR base as the target length followed by pairs of
+// (zero run, literal run) lengths, each literal run followed by its bytes
+fn compress(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    push_varint(&mut out, target.len());
+    let mut i = 0;
+    while i < target.len() {
+        let zeroes_start = i;
+        while i < target.len() && xor(i) == 0 {
+            i += 1;
+        }
+        let literals_start = i;
+        while i < target.len() && xor(i) != 0 {
+            i += 1;
+        }
+        push_varint(&mut out, literals_start - zeroes_start);
+        push_varint(&mut out, i - literals_start);
+        out.extend((literals_start..i).map(xor));
+    }
+    out
+}
+
+fn decompress(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut target: Vec<u8> = (0..len)
+        .map(|i| base.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+        for byte in &delta[pos..pos + literals] {
+            target[i] ^= byte;
+            i += 1;
+        }
+        pos += literals;
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+
+    #[test]
+    fn test_compress() {
+        let base = vec![1, 2, 3, 4, 5, 6];
+        let target = vec![1, 2, 0, 4, 5, 6, 7, 8];
+        let delta = compress(&base, &target);
+        assert_eq!(decompress(&base, &delta), target);
+        let delta = compress(&target, &base);
+        assert_eq!(decompress(&target, &delta), base);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let binary = compile(
+            r#".code
+    ld v0, 0
+loop:
+    add v0, 1
+    jp loop"#,
+        )
+        .unwrap();
+        let mut proc = Proc::binary(&binary).unwrap();
+        let mut rewind = Rewind::new(10);
+
+        let mut states = vec![];
+        for _ in 0..20 {
+            rewind.push(&proc);
+            states.push(proc.save_state());
+            proc.cycle().unwrap();
+        }
+
+        assert_eq!(rewind.len(), 10);
+        assert_eq!(rewind.rewind(&mut proc, 3).unwrap(), 3);
+        assert_eq!(proc.save_state(), states[16]);
+        assert_eq!(rewind.rewind(&mut proc, 100).unwrap(), 7);
+        assert_eq!(proc.save_state(), states[9]);
+        assert!(rewind.is_empty());
+
+        // A state that fails to load leaves the history untouched
+        for _ in 0..5 {
+            rewind.push(&proc);
+            proc.cycle().unwrap();
+        }
+        let latest = rewind.latest.clone();
+        rewind.latest.as_mut().unwrap()[0] ^= 1;
+        assert!(rewind.rewind(&mut proc, 2).is_err());
+        assert_eq!(rewind.len(), 5);
+        rewind.latest = latest;
+        assert_eq!(rewind.rewind(&mut proc, 2).unwrap(), 2);
+    }
+}
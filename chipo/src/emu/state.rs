@@ -0,0 +1,176 @@
+use crate::error::{ChipoError, Result};
+
+// Save states start with this magic number followed by the format version
+const MAGIC: &[u8; 4] = b"C8ST";
+// Version 2 added the random generator seed and state, version 3 the key
+// awaited by FX0A
+const STATE_VERSION: u8 = 3;
+
+// Appends fields to a save state, the CRC32 of the whole state is written last
+pub struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut buffer = Vec::with_capacity(0x12000);
+        buffer.extend_from_slice(MAGIC);
+        buffer.push(STATE_VERSION);
+        StateWriter { buffer }
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buffer.push(val);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.buffer.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.buffer.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.buffer.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buffer.extend_from_slice(val);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let checksum = crc32(&self.buffer);
+        self.u32(checksum);
+        self.buffer
+    }
+}
+
+// Reads back the fields in the order they were written
+pub struct StateReader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+    version: u8,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(state: &'a [u8]) -> Result<Self> {
+        if state.len() < MAGIC.len() + 1 + 4 || &state[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a chipo save state"));
+        }
+        let version = state[MAGIC.len()];
+        if version == 0 || version > STATE_VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let (content, checksum) = state.split_at(state.len() - 4);
+        if crc32(content).to_le_bytes() != checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        Ok(StateReader {
+            buffer: content,
+            pos: MAGIC.len() + 1,
+            version,
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.pos + len > self.buffer.len() {
+            return Err(invalid("unexpected end of state"));
+        }
+        let slice = &self.buffer[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.pos != self.buffer.len() {
+            return Err(invalid("trailing bytes after state"));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(reason: &str) -> ChipoError {
+    ChipoError::InvalidState(reason.to_string())
+}
+
+// Bitwise CRC-32 (IEEE), states are small enough not to need a lookup table
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(1);
+        writer.u16(0x0203);
+        writer.u32(0x0405_0607);
+        writer.u64(0x0809_0A0B_0C0D_0E0F);
+        writer.bytes(&[8, 9]);
+        let state = writer.finish();
+
+        let mut reader = StateReader::new(&state).unwrap();
+        assert_eq!(reader.u8().unwrap(), 1);
+        assert_eq!(reader.u16().unwrap(), 0x0203);
+        assert_eq!(reader.u32().unwrap(), 0x0405_0607);
+        assert_eq!(reader.u64().unwrap(), 0x0809_0A0B_0C0D_0E0F);
+        assert_eq!(reader.bytes(2).unwrap(), &[8, 9]);
+        assert!(reader.u8().is_err());
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let mut writer = StateWriter::new();
+        writer.u32(42);
+        let mut state = writer.finish();
+        state[6] ^= 0xFF;
+
+        assert!(StateReader::new(&state).is_err());
+        assert!(StateReader::new(b"nope").is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use crate::emu::{Addr, Val, Vx};
+
+// Conditions checked by Proc::cycle, reaching one returns
+// ProgramState::Watchpoint once the instruction has been executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watchpoint {
+    // A write to memory between start and end, end excluded
+    Memory { start: usize, end: usize },
+    // Register vx taking the given value
+    Register(Vx, Val),
+    // The index register taking the given value
+    Index(Addr),
+}
+
+impl Watchpoint {
+    pub fn memory(addr: usize, len: usize) -> Self {
+        Watchpoint::Memory {
+            start: addr,
+            end: addr.saturating_add(len.max(1)),
+        }
+    }
+
+    pub fn covers(&self, addr: usize) -> bool {
+        match *self {
+            Watchpoint::Memory { start, end } => start <= addr && addr < end,
+            _ => false,
+        }
+    }
+
+    // A register watchpoint only triggers when the value changes, so that it
+    // doesn't stop on every cycle after being reached
+    pub fn reached(&self, rg_before: &[Val; 16], i_before: Addr, rg: &[Val; 16], i: Addr) -> bool {
+        match *self {
+            Watchpoint::Register(vx, val) => rg_before[vx] != val && rg[vx] == val,
+            Watchpoint::Index(val) => i_before != val && i == val,
+            Watchpoint::Memory { .. } => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Watchpoint::Memory { start, end } if end - start == 1 => {
+                write!(f, "write to 0x{:03X}", start)
+            }
+            Watchpoint::Memory { start, end } => {
+                write!(f, "write to 0x{:03X}..0x{:03X}", start, end)
+            }
+            Watchpoint::Register(vx, val) => write!(f, "v{:X} == 0x{:02X}", vx, val),
+            Watchpoint::Index(val) => write!(f, "i == 0x{:03X}", val),
+        }
+    }
+}
@@ -15,6 +15,16 @@ pub enum ChipoError {
     UnknownOpCodeErr(u16),
     ParserError(ParserError),
     IOError(IOError),
+    UnknownQuirks(String),
+    InvalidState(String),
+    InvalidAddress(usize),
+    InvalidKeyPress(String),
+    InvalidMovie(String),
+    UnknownKeymap(String),
+    InvalidKeymap(String),
+    UnknownPalette(String),
+    InvalidPalette(String),
+    UnknownFilter(String),
     EmptyStack,
 }
 
@@ -36,6 +46,19 @@ impl std::fmt::Display for ChipoError {
             UnknownOpCodeErr(instr) => format!("OpCode 0x{:04X} not known", instr),
             ParserError(err) => err.to_string(),
             IOError(err) => format!("io error: {}", err),
+            UnknownQuirks(name) => format!("unknown quirks profile '{}'", name),
+            InvalidState(reason) => format!("invalid save state: {}", reason),
+            InvalidAddress(addr) => format!("address 0x{:X} is out of memory", addr),
+            InvalidMovie(reason) => format!("invalid movie: {}", reason),
+            UnknownKeymap(name) => format!("unknown keymap preset '{}'", name),
+            InvalidKeymap(line) => format!("invalid keymap line '{}'", line),
+            UnknownPalette(name) => format!("unknown palette theme '{}'", name),
+            InvalidPalette(palette) => format!("invalid palette '{}'", palette),
+            UnknownFilter(name) => format!("unknown rendering filter '{}'", name),
+            InvalidKeyPress(press) => format!(
+                "invalid key press '{}', expected <frame>:<key>[:<frames>]",
+                press
+            ),
             err => format!("error: {:?}", err),
         };
 
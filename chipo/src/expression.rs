@@ -0,0 +1,211 @@
+use std::convert::TryFrom;
+
+use crate::parser::LineError;
+
+type LineResult<T> = std::result::Result<T, LineError>;
+
+// Binary operators from the lowest to the highest precedence, as in C
+const LEVELS: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Number(i64),
+    Name(&'a str),
+    Operator(&'a str),
+    Open,
+    Close,
+}
+
+// Reads a number in decimal, hexadecimal with 0x or binary with 0b
+fn parse_literal(literal: &str) -> Option<i64> {
+    if let Some(hex) = literal.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = literal.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        literal.parse().ok()
+    }
+}
+
+fn tokenize(expr: &str) -> LineResult<Vec<Token<'_>>> {
+    let invalid = || LineError::InvalidExpression(expr.to_string());
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_alphanumeric() || c == '_' {
+            // Labels of macro expansions look like loop@3
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '@'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            tokens.push(if c.is_ascii_digit() {
+                Token::Number(parse_literal(word).ok_or_else(invalid)?)
+            } else {
+                Token::Name(word)
+            });
+            len
+        } else if rest.starts_with("<<") || rest.starts_with(">>") {
+            tokens.push(Token::Operator(&rest[..2]));
+            2
+        } else {
+            tokens.push(match c {
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' => Token::Operator(&rest[..1]),
+                _ => return Err(invalid()),
+            });
+            1
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+fn apply(operator: &str, a: i64, b: i64) -> Option<i64> {
+    match operator {
+        "|" => Some(a | b),
+        "^" => Some(a ^ b),
+        "&" => Some(a & b),
+        "<<" => u32::try_from(b).ok().and_then(|b| a.checked_shl(b)),
+        ">>" => u32::try_from(b).ok().and_then(|b| a.checked_shr(b)),
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" => a.checked_div(b),
+        "%" => a.checked_rem(b),
+        _ => None,
+    }
+}
+
+struct Evaluator<'a, 'r> {
+    expr: &'a str,
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    resolve: &'r dyn Fn(&str) -> LineResult<i64>,
+}
+
+impl<'a> Evaluator<'a, '_> {
+    fn invalid(&self) -> LineError {
+        LineError::InvalidExpression(self.expr.to_string())
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.position).copied();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn binary(&mut self, level: usize) -> LineResult<i64> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some(Token::Operator(operator)) = self.peek() {
+            if !LEVELS[level].contains(&operator) {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            value = apply(operator, value, right).ok_or_else(|| self.invalid())?;
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> LineResult<i64> {
+        match self.next() {
+            Some(Token::Operator("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(Token::Operator("+")) => self.unary(),
+            Some(Token::Operator("~")) => Ok(!self.unary()?),
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Open) => self.parenthesized(),
+            // hi(x) and lo(x) are the high and low bytes of a 16-bit value
+            Some(Token::Name(name)) if self.peek() == Some(Token::Open) => {
+                self.position += 1;
+                let value = self.parenthesized()?;
+                match name {
+                    "hi" => Ok((value >> 8) & 0xFF),
+                    "lo" => Ok(value & 0xFF),
+                    _ => Err(self.invalid()),
+                }
+            }
+            Some(Token::Name(name)) => (self.resolve)(name),
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn parenthesized(&mut self) -> LineResult<i64> {
+        let value = self.binary(0)?;
+        match self.next() {
+            Some(Token::Close) => Ok(value),
+            _ => Err(self.invalid()),
+        }
+    }
+}
+
+// Computes an operand like `sprites + 5 * (n & 3)`, with the value of the
+// constants and labels it names given by resolve
+pub fn evaluate(expr: &str, resolve: &dyn Fn(&str) -> LineResult<i64>) -> LineResult<i64> {
+    let mut evaluator = Evaluator {
+        expr,
+        tokens: tokenize(expr)?,
+        position: 0,
+        resolve,
+    };
+    let value = evaluator.binary(0)?;
+    if evaluator.position != evaluator.tokens.len() {
+        return Err(evaluator.invalid());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> LineResult<i64> {
+        evaluate(expr, &|name| match name {
+            "w" => Ok(64),
+            "sprites" => Ok(0x2A0),
+            _ => Err(LineError::UnknownSymbol(name.to_string())),
+        })
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(eval("42"), Ok(42));
+        assert_eq!(eval("w - 8"), Ok(56));
+        assert_eq!(eval("sprites + 5 * 3"), Ok(0x2AF));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("1 << 4 | 0b11 & ~1"), Ok(18));
+        assert_eq!(eval("0xF0 ^ 0xFF >> 4"), Ok(0xFF));
+        assert_eq!(eval("-w / 3 % 5"), Ok(-1));
+        assert_eq!(eval("hi(sprites) + lo(sprites)"), Ok(0xA2));
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let invalid = |expr: &str| Err(LineError::InvalidExpression(expr.to_string()));
+        assert_eq!(
+            eval("h + 1"),
+            Err(LineError::UnknownSymbol("h".to_string()))
+        );
+        assert_eq!(eval("1 +"), invalid("1 +"));
+        assert_eq!(eval("(1"), invalid("(1"));
+        assert_eq!(eval("1 2"), invalid("1 2"));
+        assert_eq!(eval("w / 0"), invalid("w / 0"));
+        assert_eq!(eval("0xZ"), invalid("0xZ"));
+        assert_eq!(eval("mid(w)"), invalid("mid(w)"));
+        assert_eq!(eval("w $ 2"), invalid("w $ 2"));
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error as IOError, ErrorKind, Result};
+
+// Gives the assembler the files included by a program, so that frontends
+// without a file system like the browser can provide their own
+pub trait FileResolver {
+    fn read(&self, path: &str) -> Result<Vec<u8>>;
+}
+
+// Reads the files from the disk, relative paths from the working directory
+pub struct FileSystem;
+
+impl FileResolver for FileSystem {
+    fn read(&self, path: &str) -> Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+// Files kept in memory by their path
+impl FileResolver for HashMap<String, Vec<u8>> {
+    fn read(&self, path: &str) -> Result<Vec<u8>> {
+        self.get(path)
+            .cloned()
+            .ok_or_else(|| IOError::new(ErrorKind::NotFound, "file not found"))
+    }
+}
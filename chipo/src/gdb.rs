@@ -0,0 +1,597 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::emu::{Addr, Proc, ProgramState, Watchpoint};
+use crate::error::Result;
+
+// Signals sent in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Checking the socket for an interrupt on every cycle would be too slow
+const INTERRUPT_CHECK_CYCLES: usize = 256;
+
+// Registers are numbered v0 to vF, then i, pc, dt and st. The 16 bits
+// registers are sent big-endian, as they are stored in memory.
+const REGISTER_COUNT: usize = 20;
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_DT: usize = 18;
+const REG_ST: usize = 19;
+
+#[derive(Clone, Copy)]
+enum Stop {
+    // Waiting for the debugger to ask why the target stopped
+    Attach,
+    Signal(u8),
+    Watch(Watchpoint),
+}
+
+enum Resume {
+    Continue,
+    Detach,
+    Kill,
+}
+
+// Stub of the GDB remote serial protocol serving a single debugger
+// connection. It replaces Proc::cycle in the emulation loop and blocks while
+// the debugger has the target stopped.
+pub struct GdbStub {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    breakpoints: BTreeSet<usize>,
+    stop: Option<Stop>,
+    last_stop: Stop,
+    stepping: bool,
+    // Set when resuming so that the breakpoint at pc isn't hit again right away
+    resuming: bool,
+    detached: bool,
+    interrupt_countdown: usize,
+}
+
+impl GdbStub {
+    // Waits for a debugger to connect on addr
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        GdbStub::new(stream)
+    }
+
+    pub fn new(stream: TcpStream) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            breakpoints: BTreeSet::new(),
+            stop: Some(Stop::Attach),
+            last_stop: Stop::Signal(SIGTRAP),
+            stepping: false,
+            resuming: false,
+            detached: false,
+            interrupt_countdown: 0,
+        })
+    }
+
+    // Executes the next instruction, serving the debugger first when the
+    // target is stopped. Watchpoints reached are handled by the debugger.
+    pub fn cycle(&mut self, proc: &mut Proc) -> Result<ProgramState> {
+        if self.detached {
+            return proc.cycle();
+        }
+
+        if self.stop.is_none() {
+            if self.breakpoints.contains(&proc.pc()) && !self.resuming {
+                self.stop = Some(Stop::Signal(SIGTRAP));
+            } else if self.interrupted()? {
+                self.stop = Some(Stop::Signal(SIGINT));
+            }
+        }
+        self.resuming = false;
+
+        if let Some(stop) = self.stop.take() {
+            match self.serve(proc, stop)? {
+                Resume::Continue => self.resuming = true,
+                Resume::Detach => {
+                    self.detached = true;
+                    proc.clear_watchpoints();
+                }
+                Resume::Kill => return Ok(ProgramState::Stop),
+            }
+        }
+
+        let state = proc.cycle();
+        match state {
+            Ok(ProgramState::Watchpoint(watchpoint)) if !self.detached => {
+                self.stop = Some(Stop::Watch(watchpoint));
+                return Ok(ProgramState::Continue);
+            }
+            Ok(ProgramState::Continue) if self.stepping => {
+                self.stop = Some(Stop::Signal(SIGTRAP));
+            }
+            Ok(ProgramState::Stop) if !self.detached => self.send("W00")?,
+            Err(_) if !self.detached => {
+                self.send(&format!("X{:02x}", SIGILL))?;
+            }
+            _ => {}
+        }
+        state
+    }
+
+    // Looks for the interrupt byte the debugger sends while the target runs
+    fn interrupted(&mut self) -> Result<bool> {
+        if self.interrupt_countdown > 0 {
+            self.interrupt_countdown -= 1;
+            return Ok(false);
+        }
+        self.interrupt_countdown = INTERRUPT_CHECK_CYCLES;
+        loop {
+            if self.reader.buffer().is_empty() {
+                self.reader.get_ref().set_nonblocking(true)?;
+                let filled = self.reader.fill_buf().map(|buffer| buffer.len());
+                self.reader.get_ref().set_nonblocking(false)?;
+                match filled {
+                    Ok(0) => {
+                        // The debugger went away, let the program run on its own
+                        self.detached = true;
+                        return Ok(false);
+                    }
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            match self.reader.buffer()[0] {
+                // Acks of the last reply
+                b'+' | b'-' => self.reader.consume(1),
+                0x03 => {
+                    self.reader.consume(1);
+                    return Ok(true);
+                }
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    fn serve(&mut self, proc: &mut Proc, stop: Stop) -> Result<Resume> {
+        if !matches!(stop, Stop::Attach) {
+            self.last_stop = stop;
+            self.send(&stop_reply(stop))?;
+        }
+
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(Resume::Detach),
+            };
+            let (command, args) = if packet.is_char_boundary(1) {
+                packet.split_at(1)
+            } else {
+                ("", packet.as_str())
+            };
+            let reply = match command {
+                "?" => stop_reply(self.last_stop),
+                "g" => (0..REGISTER_COUNT)
+                    .map(|reg| to_hex(&read_register(proc, reg)))
+                    .collect(),
+                "G" => write_registers(proc, args),
+                "p" => usize::from_str_radix(args, 16)
+                    .ok()
+                    .filter(|&reg| reg < REGISTER_COUNT)
+                    .map(|reg| to_hex(&read_register(proc, reg)))
+                    .unwrap_or_else(|| "E01".to_string()),
+                "P" => write_single_register(proc, args),
+                "m" => read_memory(proc, args),
+                "M" => write_memory(proc, args),
+                "Z" | "z" => self.set_breakpoint(proc, command == "Z", args),
+                "s" | "c" => {
+                    if let Some(addr) = parse_hex(args) {
+                        if !valid_pc(proc, addr) {
+                            self.send("E01")?;
+                            continue;
+                        }
+                        proc.set_pc(addr);
+                    }
+                    self.stepping = command == "s";
+                    return Ok(Resume::Continue);
+                }
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(Resume::Detach);
+                }
+                "k" => return Ok(Resume::Kill),
+                "H" | "T" => "OK".to_string(),
+                "q" => query(args),
+                _ if packet == "vKill" || packet.starts_with("vKill;") => {
+                    self.send("OK")?;
+                    return Ok(Resume::Kill);
+                }
+                _ => String::new(),
+            };
+            self.send(&reply)?;
+        }
+    }
+
+    // Software and hardware breakpoints are the same, write watchpoints map
+    // to the processor memory watchpoints
+    fn set_breakpoint(&mut self, proc: &mut Proc, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(parse_hex);
+        let len = fields.next().and_then(parse_hex).unwrap_or(1);
+        match (kind, addr) {
+            (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                "OK".to_string()
+            }
+            (Some("2"), Some(addr)) => {
+                let watchpoint = Watchpoint::memory(addr, len);
+                if insert {
+                    proc.add_watchpoint(watchpoint);
+                } else {
+                    proc.remove_watchpoint(&watchpoint);
+                }
+                "OK".to_string()
+            }
+            (Some(_), Some(_)) => String::new(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Reads the next packet, acknowledging it. None when the connection is closed.
+    fn read_packet(&mut self) -> Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            if self.reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            // Acks and interrupts received while stopped are ignored
+            if byte[0] != b'$' {
+                continue;
+            }
+
+            let mut packet = vec![];
+            if self.reader.read_until(b'#', &mut packet)? == 0 || packet.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+            if expected == Some(checksum_of(&packet)) {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&packet).into_owned()));
+            }
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.writer.write_all(packet.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Watch(Watchpoint::Memory { start, .. }) => {
+            format!("T{:02x}watch:{:x};", SIGTRAP, start)
+        }
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        "PacketSize=1000;qXfer:features:read+".to_string()
+    } else if args == "Attached" {
+        "1".to_string()
+    } else if args == "C" {
+        "QC1".to_string()
+    } else if args == "fThreadInfo" {
+        "m1".to_string()
+    } else if args == "sThreadInfo" {
+        "l".to_string()
+    } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        let mut range = range.split(',').map(parse_hex);
+        match (range.next().flatten(), range.next().flatten()) {
+            (Some(offset), Some(len)) => {
+                let xml = target_xml();
+                let chunk = xml.get(offset..).unwrap_or("");
+                if chunk.len() > len {
+                    format!("m{}", &chunk[..len])
+                } else {
+                    format!("l{}", chunk)
+                }
+            }
+            _ => "E01".to_string(),
+        }
+    } else {
+        String::new()
+    }
+}
+
+// Describes the registers to the debugger, as CHIP-8 isn't a known architecture
+fn target_xml() -> String {
+    let mut regs = (0..16)
+        .map(|vx| format!(r#"<reg name="v{:x}" bitsize="8" type="uint8"/>"#, vx))
+        .collect::<Vec<String>>();
+    regs.push(r#"<reg name="i" bitsize="16" type="data_ptr"/>"#.to_string());
+    regs.push(r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#.to_string());
+    regs.push(r#"<reg name="dt" bitsize="8" type="uint8"/>"#.to_string());
+    regs.push(r#"<reg name="st" bitsize="8" type="uint8"/>"#.to_string());
+    format!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><feature name="org.chipo.chip8">{}</feature></target>"#,
+        regs.join("")
+    )
+}
+
+fn register_size(reg: usize) -> usize {
+    match reg {
+        REG_I | REG_PC => 2,
+        _ => 1,
+    }
+}
+
+fn read_register(proc: &Proc, reg: usize) -> Vec<u8> {
+    match reg {
+        REG_I => (proc.i() as u16).to_be_bytes().to_vec(),
+        REG_PC => (proc.pc() as u16).to_be_bytes().to_vec(),
+        REG_DT => vec![proc.delay_timer()],
+        REG_ST => vec![proc.sound_timer()],
+        vx => vec![proc.registers()[vx]],
+    }
+}
+
+// Returns false when the value is rejected, only pc can be out of range
+fn write_register(proc: &mut Proc, reg: usize, bytes: &[u8]) -> bool {
+    let word = || ((bytes[0] as u16) << 8) + bytes[1] as u16;
+    match reg {
+        REG_I => proc.set_i(word() as Addr),
+        REG_PC if !valid_pc(proc, word() as usize) => return false,
+        REG_PC => proc.set_pc(word() as usize),
+        REG_DT => proc.set_delay_timer(bytes[0]),
+        REG_ST => proc.set_sound_timer(bytes[0]),
+        vx => proc.set_register(vx, bytes[0]),
+    }
+    true
+}
+
+// A whole instruction has to be readable at pc
+fn valid_pc(proc: &Proc, addr: usize) -> bool {
+    addr < proc.memory().len() - 1
+}
+
+fn write_registers(proc: &mut Proc, args: &str) -> String {
+    let bytes = match from_hex(args) {
+        Some(bytes) => bytes,
+        None => return "E01".to_string(),
+    };
+    let mut pos = 0;
+    for reg in 0..REGISTER_COUNT {
+        let size = register_size(reg);
+        if pos + size > bytes.len() {
+            break;
+        }
+        if !write_register(proc, reg, &bytes[pos..pos + size]) {
+            return "E01".to_string();
+        }
+        pos += size;
+    }
+    "OK".to_string()
+}
+
+fn write_single_register(proc: &mut Proc, args: &str) -> String {
+    let mut fields = args.splitn(2, '=');
+    let reg = fields.next().and_then(parse_hex);
+    let bytes = fields.next().and_then(from_hex);
+    match (reg, bytes) {
+        (Some(reg), Some(bytes))
+            if reg < REGISTER_COUNT
+                && bytes.len() == register_size(reg)
+                && write_register(proc, reg, &bytes) =>
+        {
+            "OK".to_string()
+        }
+        _ => "E01".to_string(),
+    }
+}
+
+fn read_memory(proc: &Proc, args: &str) -> String {
+    let mut fields = args.splitn(2, ',').map(parse_hex);
+    match (fields.next().flatten(), fields.next().flatten()) {
+        (Some(addr), Some(len)) => match addr
+            .checked_add(len)
+            .and_then(|end| proc.memory().get(addr..end))
+        {
+            Some(bytes) => to_hex(bytes),
+            None => "E01".to_string(),
+        },
+        _ => "E01".to_string(),
+    }
+}
+
+fn write_memory(proc: &mut Proc, args: &str) -> String {
+    let mut fields = args.splitn(2, ':');
+    let mut range = fields.next().unwrap_or("").splitn(2, ',').map(parse_hex);
+    let bytes = fields.next().and_then(from_hex);
+    match (range.next().flatten(), range.next().flatten(), bytes) {
+        (Some(addr), Some(len), Some(bytes)) if bytes.len() == len => {
+            match proc.write_memory(addr, &bytes) {
+                Ok(()) => "OK".to_string(),
+                Err(_) => "E01".to_string(),
+            }
+        }
+        _ => "E01".to_string(),
+    }
+}
+
+fn parse_hex(val: &str) -> Option<usize> {
+    usize::from_str_radix(val, 16).ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|pos| u8::from_str_radix(hex.get(pos..pos + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+    use std::thread;
+
+    struct Client {
+        stream: BufReader<TcpStream>,
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.stream.get_mut().write_all(packet.as_bytes()).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = vec![];
+            loop {
+                let mut byte = [0];
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            self.stream.read_until(b'#', &mut packet).unwrap();
+            packet.pop();
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.get_mut().write_all(b"+").unwrap();
+            String::from_utf8(packet).unwrap()
+        }
+    }
+
+    // Registers and memory of the program once it stopped
+    type Finished = thread::JoinHandle<(Vec<u8>, Vec<u8>)>;
+
+    // Runs the program behind a stub in another thread
+    fn connect(asm: &str) -> (Client, Finished) {
+        let binary = compile(asm).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stub = GdbStub::new(stream).unwrap();
+            let mut proc = Proc::binary(&binary).unwrap();
+            while let Ok(ProgramState::Continue) = stub.cycle(&mut proc) {}
+            (proc.registers().to_vec(), proc.memory().to_vec())
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let client = Client {
+            stream: BufReader::new(stream),
+        };
+        (client, handle)
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+        assert_eq!(from_hex("0a1B"), Some(vec![0x0a, 0x1b]));
+        assert_eq!(from_hex("0a1"), None);
+    }
+
+    #[test]
+    fn test_session() {
+        let (mut client, handle) = connect(
+            r#".code
+    ld v0, 1
+    ld v1, 2
+loop:
+    add v0, 1
+    se v0, 5
+    jp loop
+    ld i, 0x300
+    ld [i], v1
+    ret"#,
+        );
+
+        assert!(client
+            .request("qSupported")
+            .contains("qXfer:features:read+"));
+        assert!(client
+            .request("qXfer:features:read:target.xml:0,fff")
+            .starts_with("l<?xml"));
+        assert_eq!(client.request("?"), "S05");
+        assert!(client.request("g").starts_with("0000"));
+        assert_eq!(client.request("p11"), "0200");
+
+        // Step over the two first instructions
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "01");
+        assert_eq!(client.request("p1"), "02");
+
+        // Break in the loop, then change v0 to leave it early
+        assert_eq!(client.request("Z0,204,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p0"), "02");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p0"), "03");
+        assert_eq!(client.request("P0=04"), "OK");
+        assert_eq!(client.request("z0,204,2"), "OK");
+
+        // Watch the store and patch memory read back afterwards
+        assert_eq!(client.request("Z2,300,1"), "OK");
+        assert_eq!(client.request("c"), "T05watch:300;");
+        assert_eq!(client.request("m300,2"), "0502");
+        assert_eq!(client.request("M301,1:2a"), "OK");
+        assert_eq!(client.request("m300,2"), "052a");
+        assert_eq!(client.request("m10000,1"), "E01");
+        assert_eq!(client.request("mffffffffffffffff,2"), "E01");
+        assert_eq!(client.request("Mffffffffffffffff,2:0000"), "E01");
+        assert_eq!(client.request("P11=ffff"), "E01");
+        assert_eq!(client.request("c10000"), "E01");
+        assert_eq!(client.request("c"), "W00");
+
+        let (registers, memory) = handle.join().unwrap();
+        assert_eq!(registers[0], 5);
+        assert_eq!(memory[0x301], 0x2a);
+    }
+
+    #[test]
+    fn test_detach() {
+        let (mut client, handle) = connect(
+            r#".code
+    ld v0, 1
+    ld v1, 2
+    ret"#,
+        );
+        assert_eq!(client.request("G0304"), "OK");
+        assert_eq!(client.request("D"), "OK");
+
+        let (registers, _) = handle.join().unwrap();
+        assert_eq!(registers[..2], [1, 2]);
+    }
+}
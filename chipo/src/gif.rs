@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+// Codes are at most 12 bits long, the table is cleared when it is full
+const MAX_CODES: u16 = 4096;
+
+// Encodes an animated GIF of indexed frames, looping forever.
+// Palettes have up to 256 colors and frames one palette index per pixel.
+pub struct GifEncoder {
+    width: u16,
+    height: u16,
+    buffer: Vec<u8>,
+    min_code_size: u8,
+}
+
+impl GifEncoder {
+    pub fn new(width: u16, height: u16, palette: &[[u8; 3]]) -> Self {
+        // The color table has a power of two entries, at least 2
+        let bits = (1..=8).find(|bits| 1 << bits >= palette.len()).unwrap_or(8);
+        let mut buffer = b"GIF89a".to_vec();
+        buffer.extend_from_slice(&width.to_le_bytes());
+        buffer.extend_from_slice(&height.to_le_bytes());
+        buffer.push(0x80 | (bits as u8 - 1) << 4 | (bits as u8 - 1));
+        buffer.extend_from_slice(&[0, 0]);
+        for i in 0..1 << bits {
+            buffer.extend_from_slice(palette.get(i).unwrap_or(&[0, 0, 0]));
+        }
+        // NETSCAPE2.0 application extension, looping forever
+        buffer.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+        GifEncoder {
+            width,
+            height,
+            buffer,
+            min_code_size: bits.max(2) as u8,
+        }
+    }
+
+    // Adds a frame shown for delay hundredths of a second
+    pub fn add_frame(&mut self, pixels: &[u8], delay: u16) {
+        assert_eq!(pixels.len(), self.width as usize * self.height as usize);
+        // Graphic control extension with the delay
+        self.buffer.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+        self.buffer.extend_from_slice(&delay.to_le_bytes());
+        self.buffer.extend_from_slice(&[0x00, 0x00]);
+        // Image descriptor covering the whole screen
+        self.buffer.extend_from_slice(&[0x2C, 0, 0, 0, 0]);
+        self.buffer.extend_from_slice(&self.width.to_le_bytes());
+        self.buffer.extend_from_slice(&self.height.to_le_bytes());
+        self.buffer.push(0x00);
+
+        self.buffer.push(self.min_code_size);
+        for block in lzw(pixels, self.min_code_size).chunks(255) {
+            self.buffer.push(block.len() as u8);
+            self.buffer.extend_from_slice(block);
+        }
+        self.buffer.push(0x00);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buffer.push(0x3B);
+        self.buffer
+    }
+}
+
+// Packs codes of varying sizes, least significant bits first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.acc |= (code as u32) << self.len;
+        self.len += size;
+        while self.len >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+// Variable-length LZW compression as used by GIF. The code size grows as the
+// table fills, at the same points as the decoder which adds its entries one
+// code late.
+fn lzw(data: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let first = clear + 2;
+    let mut table = HashMap::new();
+    let mut next = first;
+    let mut size = min_code_size + 1;
+    let mut writer = BitWriter::default();
+    writer.write(clear, size);
+
+    let mut prefix = match data.first() {
+        Some(&b) => b as u16,
+        None => {
+            writer.write(clear + 1, size);
+            return writer.finish();
+        }
+    };
+    for &b in &data[1..] {
+        if let Some(&code) = table.get(&(prefix, b)) {
+            prefix = code;
+            continue;
+        }
+        writer.write(prefix, size);
+        if next == MAX_CODES {
+            writer.write(clear, size);
+            table.clear();
+            next = first;
+            size = min_code_size + 1;
+        } else {
+            table.insert((prefix, b), next);
+            if next == 1 << size {
+                size += 1;
+            }
+            next += 1;
+        }
+        prefix = b as u16;
+    }
+    writer.write(prefix, size);
+    if next == 1 << size && size < 12 {
+        size += 1;
+    }
+    writer.write(clear + 1, size);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference decoder following the GIF specification
+    fn unlzw(data: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut out = vec![];
+        let mut table: Vec<Vec<u8>> = vec![];
+        let mut size = min_code_size + 1;
+        let mut prev: Option<Vec<u8>> = None;
+        let (mut acc, mut len, mut pos) = (0u32, 0u8, 0);
+        loop {
+            while len < size {
+                acc |= (data[pos] as u32) << len;
+                pos += 1;
+                len += 8;
+            }
+            let code = (acc & ((1 << size) - 1)) as usize;
+            acc >>= size;
+            len -= size;
+
+            if code == clear {
+                table = (0..clear + 2).map(|b| vec![b as u8]).collect();
+                size = min_code_size + 1;
+                prev = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let entry = match (&prev, table.get(code)) {
+                (_, Some(entry)) => entry.clone(),
+                (Some(prev), None) => [&prev[..], &prev[..1]].concat(),
+                (None, None) => panic!("invalid first code"),
+            };
+            if let Some(prev) = prev {
+                if table.len() < MAX_CODES as usize {
+                    table.push([&prev[..], &entry[..1]].concat());
+                }
+            }
+            if table.len() == 1 << size && size < 12 {
+                size += 1;
+            }
+            out.extend_from_slice(&entry);
+            prev = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_lzw() {
+        let samples: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![3],
+            vec![0; 10000],
+            (0..20000).map(|i| ((i * 7 + i / 13) % 4) as u8).collect(),
+            (0..50000u64).map(|i| (i * i % 251 % 4) as u8).collect(),
+        ];
+        for data in samples {
+            assert_eq!(unlzw(&lzw(&data, 2), 2), data);
+        }
+        let bytes = (0..30000)
+            .map(|i| (i * 31 % 256) as u8)
+            .collect::<Vec<u8>>();
+        assert_eq!(unlzw(&lzw(&bytes, 8), 8), bytes);
+    }
+
+    #[test]
+    fn test_gif() {
+        let mut gif = GifEncoder::new(4, 2, &[[255, 255, 255], [0, 0, 0], [255, 0, 0]]);
+        gif.add_frame(&[0, 1, 2, 3, 3, 2, 1, 0], 5);
+        let data = gif.finish();
+        assert_eq!(&data[..6], b"GIF89a");
+        assert_eq!(&data[6..10], &[4, 0, 2, 0]);
+        // Four colors in the global table
+        assert_eq!(data[10], 0x91);
+        assert_eq!(&data[13..25], &[255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 0, 0]);
+        assert_eq!(data.last(), Some(&0x3B));
+    }
+}
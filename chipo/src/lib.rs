@@ -1,24 +1,67 @@
 pub mod emu;
 pub mod error;
+mod expression;
+pub mod files;
+pub mod gdb;
+pub mod gif;
+pub mod palette;
 mod parser;
+pub mod screenshot;
 
-use crate::emu::Instruction;
+use std::collections::HashMap;
+
+use crate::emu::{Addr, Instruction};
 use crate::error::{ChipoError, Result};
-use crate::parser::parse;
+use crate::files::{FileResolver, FileSystem};
+use crate::parser::parse_with_symbols;
 
-pub fn compile(asm: &str) -> Result<Vec<u8>> {
-    Ok(parse(asm)?
+fn to_bytes(instructions: &[Instruction]) -> Vec<u8> {
+    instructions
         .iter()
-        .map(|inst| inst.to_bin())
-        .flat_map(|b| vec![(b >> 8) as u8, b as u8].into_iter())
-        .collect::<Vec<u8>>())
+        .flat_map(|inst| inst.to_bytes().into_iter())
+        .collect::<Vec<u8>>()
+}
+
+// Included files are read from the disk, relative to the working directory
+pub fn compile(asm: &str) -> Result<Vec<u8>> {
+    compile_with_files(asm, &FileSystem)
+}
+
+pub fn compile_with_files(asm: &str, files: &dyn FileResolver) -> Result<Vec<u8>> {
+    let (instructions, _) = parse_with_symbols(asm, None, files)?;
+    Ok(to_bytes(&instructions))
+}
+
+// Compiles the program and returns the address of each label with it
+pub fn compile_with_symbols(asm: &str) -> Result<(Vec<u8>, HashMap<String, Addr>)> {
+    let (instructions, symbols) = parse_with_symbols(asm, None, &FileSystem)?;
+    Ok((to_bytes(&instructions), symbols))
+}
+
+// Compiles the file at path, errors give the file of the line with it
+pub fn compile_file_with_symbols(
+    path: &str,
+    files: &dyn FileResolver,
+) -> Result<(Vec<u8>, HashMap<String, Addr>)> {
+    let asm = String::from_utf8(files.read(path)?)
+        .map_err(|_| ChipoError::InvalidFile(path.to_string()))?;
+    let (instructions, symbols) = parse_with_symbols(&asm, Some(path), files)?;
+    Ok((to_bytes(&instructions), symbols))
 }
 
 pub fn reverse_parse(tokens: &[u8]) -> Result<String> {
     let mut instructions = Vec::with_capacity(tokens.len() / 2);
-    for i in 0..(tokens.len() / 2) {
-        let val = ((tokens[i * 2] as u16) << 8) + (tokens[i * 2 + 1] as u16);
-        let value = match Instruction::from(val) {
+    let mut i = 0;
+    while i + 1 < tokens.len() {
+        let val = ((tokens[i] as u16) << 8) + (tokens[i + 1] as u16);
+        let decoded = if Instruction::is_long(val) && i + 3 < tokens.len() {
+            let operand = ((tokens[i + 2] as u16) << 8) + (tokens[i + 3] as u16);
+            Instruction::from_long(val, operand)
+        } else {
+            Instruction::from(val)
+        };
+        i += decoded.as_ref().map_or(2, Instruction::size);
+        let value = match decoded {
             Ok(inst) => inst.to_asm(),
             // TODO: Group raw instructions to data section
             Err(ChipoError::UnknownOpCodeErr(..)) => Instruction::Raw(val).to_asm(),
@@ -47,4 +90,26 @@ mod tests {
         let res = reverse_parse(&tokens).unwrap();
         assert_eq!(res, code);
     }
+
+    #[test]
+    fn test_symbols() {
+        let (tokens, symbols) = compile_with_symbols(
+            r#".data
+sprite: 0xFFFF
+
+.code
+start:
+  ld i, sprite
+loop:
+  jp loop"#,
+        )
+        .unwrap();
+        assert_eq!(
+            tokens,
+            compile(".data\nsprite: 0xFFFF\n\n.code\nld i, sprite\njp 0x206").unwrap()
+        );
+        assert_eq!(symbols["sprite"], 0x202);
+        assert_eq!(symbols["start"], 0x204);
+        assert_eq!(symbols["loop"], 0x206);
+    }
 }
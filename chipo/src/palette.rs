@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{ChipoError, Result};
+
+pub type Rgb = [u8; 3];
+
+// Named themes, with the background then the colors of the pixels set in
+// plane 1, plane 2 and both planes
+pub const THEMES: [(&str, [Rgb; 4]); 6] = [
+    (
+        "chipo",
+        [
+            [252, 252, 252],
+            [38, 84, 124],
+            [239, 71, 111],
+            [6, 214, 160],
+        ],
+    ),
+    (
+        "neon",
+        [[50, 50, 50], [0, 255, 100], [239, 71, 111], [255, 209, 102]],
+    ),
+    (
+        "mono",
+        [[0, 0, 0], [255, 255, 255], [170, 170, 170], [85, 85, 85]],
+    ),
+    (
+        "octo",
+        [[153, 102, 0], [255, 204, 0], [255, 102, 0], [102, 34, 0]],
+    ),
+    (
+        "gameboy",
+        [[155, 188, 15], [15, 56, 15], [48, 98, 48], [139, 172, 15]],
+    ),
+    (
+        "amber",
+        [[20, 12, 0], [255, 176, 0], [170, 100, 0], [255, 220, 120]],
+    ),
+];
+
+// Names of the colors in palette configurations, in the same order
+const NAMES: [&str; 4] = ["background", "plane1", "plane2", "both"];
+
+// Colors of the screen, four of them to draw the two XO-CHIP planes.
+// Programs using a single plane only show the background and plane 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    colors: [Rgb; 4],
+}
+
+fn parse_color(hex: &str) -> Option<Rgb> {
+    let hex = hex.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+}
+
+impl Palette {
+    pub fn new(colors: [Rgb; 4]) -> Self {
+        Palette { colors }
+    }
+
+    pub fn theme(name: &str) -> Option<Self> {
+        THEMES
+            .iter()
+            .find(|(theme, _)| theme.eq_ignore_ascii_case(name))
+            .map(|(_, colors)| Palette::new(*colors))
+    }
+
+    pub fn colors(&self) -> &[Rgb; 4] {
+        &self.colors
+    }
+
+    // Color of a pixel from its plane bits
+    pub fn color(&self, planes: u8) -> Rgb {
+        self.colors[planes as usize & 0x3]
+    }
+
+    pub fn set_color(&mut self, planes: u8, color: Rgb) {
+        self.colors[planes as usize & 0x3] = color;
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.color(0)
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.color(1)
+    }
+
+    // Applies a palette configuration, made of lines like
+    //   theme octo
+    //   background = #000000
+    //   plane1 = ffffff
+    // setting the background, plane1, plane2 or both colors, and # followed
+    // by a space starts a comment.
+    pub fn configure(&mut self, config: &str) -> Result<()> {
+        for line in config.lines() {
+            let line = line.split("# ").next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || ChipoError::InvalidPalette(line.to_string());
+
+            if let Some(theme) = line.strip_prefix("theme ") {
+                *self = theme.trim().parse()?;
+            } else if let Some((name, color)) = line.split_once('=') {
+                let planes = NAMES
+                    .iter()
+                    .position(|&n| n == name.trim().to_lowercase())
+                    .ok_or_else(invalid)?;
+                self.colors[planes] = parse_color(color).ok_or_else(invalid)?;
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::new(THEMES[0].1)
+    }
+}
+
+// Written as the four colors in hexadecimal, like #fcfcfc,#26547c,...
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let colors = self
+            .colors
+            .iter()
+            .map(|[r, g, b]| format!("#{:02x}{:02x}{:02x}", r, g, b))
+            .collect::<Vec<String>>();
+        f.write_str(&colors.join(","))
+    }
+}
+
+// Reads the name of a theme, or four colors written as hexadecimal RGB and
+// separated by commas, like fcfcfc,26547c,ef476f,06d6a0
+impl FromStr for Palette {
+    type Err = ChipoError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if !s.contains(',') {
+            return Palette::theme(s.trim())
+                .ok_or_else(|| ChipoError::UnknownPalette(s.to_string()));
+        }
+        let invalid = || ChipoError::InvalidPalette(s.to_string());
+        let colors = s.split(',').collect::<Vec<&str>>();
+        if colors.len() != 4 {
+            return Err(invalid());
+        }
+        let mut palette = Palette::default();
+        for (color, hex) in palette.colors.iter_mut().zip(colors) {
+            *color = parse_color(hex).ok_or_else(invalid)?;
+        }
+        Ok(palette)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "fcfcfc,26547c,ef476f,06d6a0".parse::<Palette>().unwrap(),
+            Palette::default()
+        );
+        let palette = "#000000, #FFFFFF, #ff0000, #00ff00"
+            .parse::<Palette>()
+            .unwrap();
+        assert_eq!(palette.foreground(), [255, 255, 255]);
+        assert_eq!(palette.color(3), [0, 255, 0]);
+        assert_eq!(palette.to_string().parse::<Palette>().unwrap(), palette);
+
+        assert_eq!(
+            "Octo".parse::<Palette>().unwrap().background(),
+            [153, 102, 0]
+        );
+        assert!("000000,ffffff".parse::<Palette>().is_err());
+        assert!("000,fff,f00,0f0".parse::<Palette>().is_err());
+        assert!("sepia".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn test_configure() {
+        let mut palette = Palette::default();
+        palette
+            .configure("# mine\ntheme mono\n\nplane2 = #ff0000 # red\nBoth=00ff00\n")
+            .unwrap();
+        assert_eq!(
+            palette.colors(),
+            &[[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 255, 0]]
+        );
+
+        assert!(Palette::default().configure("plane3 = #000000").is_err());
+        assert!(Palette::default().configure("background #000000").is_err());
+        assert!(Palette::default().configure("theme sepia").is_err());
+    }
+}
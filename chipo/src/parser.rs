@@ -1,8 +1,12 @@
 use std::collections::HashMap;
-use std::convert::From;
+use std::convert::{From, TryFrom};
 use std::num::ParseIntError;
+use std::path::Path;
+use std::rc::Rc;
 
 use crate::emu::{Addr, Instruction, Vx};
+use crate::expression::evaluate;
+use crate::files::FileResolver;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum LineError {
@@ -14,26 +18,55 @@ pub enum LineError {
     ParseIntErr(ParseIntError),
     InvalidAddress(String),
     DuplicateAddress(String),
+    DuplicateConstant(String),
+    ConstantIsRegister(String),
+    InvalidMacro(String),
+    DuplicateMacro(String),
+    UnterminatedMacro(String),
+    RecursiveMacro(String),
+    InvalidExpression(String),
+    UnknownSymbol(String),
+    ValueOutOfRange(String),
+    IncludeErr(String, String),
+    RecursiveInclude(String),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParserError {
     NoCodeSection,
+    // The file is only known for included files and compiled files
     LineErr {
+        file: Option<String>,
         line_number: usize,
         error: LineError,
     },
+    // An error in the expansion of a macro used at line_number
+    MacroErr {
+        name: String,
+        file: Option<String>,
+        line_number: usize,
+        error: Box<ParserError>,
+    },
 }
 
 impl ParserError {
     pub fn line(number: usize, err: LineError) -> Self {
         ParserError::LineErr {
+            file: None,
             line_number: number,
             error: err,
         }
     }
 }
 
+// Written file.s:12 when the file is known, line 12 otherwise
+fn location(file: &Option<String>, line_number: usize) -> String {
+    match file {
+        Some(file) => format!("{}:{}", file, line_number),
+        None => format!("line {}", line_number),
+    }
+}
+
 impl std::fmt::Display for LineError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use LineError::*;
@@ -41,6 +74,8 @@ impl std::fmt::Display for LineError {
             UnknownSection(section) => format!("unknown section '{}'", section),
             InvalidAddress(address) => format!("address '{}' is invalid", address),
             DuplicateAddress(address) => format!("address '{}' has already been declared", address),
+            DuplicateConstant(name) => format!("constant '{}' has already been declared", name),
+            ConstantIsRegister(name) => format!("constant '{}' is a register name", name),
             WrongNumberOfArguments(expected, received) => format!(
                 "wrong number of arguments expected {} but got {}",
                 expected, received
@@ -49,6 +84,15 @@ impl std::fmt::Display for LineError {
             InstructionErr(instruction) => format!("wrong instruction: '{}'", instruction),
             RegisterErr(register) => format!("invalid register '{}'", register),
             ParseIntErr(..) => String::from("invalid integer"),
+            InvalidMacro(line) => format!("invalid macro definition '{}'", line),
+            DuplicateMacro(name) => format!("macro '{}' has already been declared", name),
+            UnterminatedMacro(name) => format!("macro '{}' is missing its .endm", name),
+            RecursiveMacro(name) => format!("macro '{}' expands itself endlessly", name),
+            InvalidExpression(expr) => format!("invalid expression '{}'", expr),
+            UnknownSymbol(name) => format!("unknown constant or label '{}'", name),
+            ValueOutOfRange(expr) => format!("value of '{}' is out of range", expr),
+            IncludeErr(path, reason) => format!("could not include '{}': {}", path, reason),
+            RecursiveInclude(path) => format!("'{}' includes itself endlessly", path),
         };
         f.write_str(&value)
     }
@@ -59,9 +103,24 @@ impl std::fmt::Display for ParserError {
         use ParserError::*;
         let value = match self {
             NoCodeSection => "missing .code section".to_string(),
-            LineErr { line_number, error } => {
-                format!("line {}: {}", line_number, error.to_string(),)
+            LineErr {
+                file,
+                line_number,
+                error,
+            } => {
+                format!("{}: {}", location(file, *line_number), error.to_string(),)
             }
+            MacroErr {
+                name,
+                file,
+                line_number,
+                error,
+            } => format!(
+                "{}: in macro '{}': {}",
+                location(file, *line_number),
+                name,
+                error
+            ),
         };
         f.write_str(&value)
     }
@@ -90,8 +149,34 @@ fn parse_register(reg: &str) -> LineResult<Vx> {
     }
 }
 
+// Shifts take an optional source register, which defaults to the shifted one
+fn parse_shift_registers(tokens: &[&str]) -> LineResult<(Vx, Vx)> {
+    match tokens.len() {
+        1 => {
+            let vx = parse_register(tokens[0])?;
+            Ok((vx, vx))
+        }
+        2 => Ok((parse_register(tokens[0])?, parse_register(tokens[1])?)),
+        _ => Err(LineError::WrongNumberOfArguments(1, tokens.len())),
+    }
+}
+
+// Only the XO-CHIP `ld i, long addr` instruction spans 4 bytes
+fn instruction_size(line: &str) -> usize {
+    let ir = line.to_lowercase();
+    match ir.split(',').nth(1) {
+        Some(operand) if ir.starts_with("ld ") && operand.trim().starts_with("long ") => 4,
+        _ => 2,
+    }
+}
+
+// Constants can be defined with each other, up to this depth
+const MAX_CONSTANT_DEPTH: usize = 32;
+
 struct Parser<'a> {
     known_addresses: HashMap<&'a str, usize>,
+    // Expressions of the constants, by lowercase name as operands are
+    constants: HashMap<String, String>,
     current_pointer: u32,
 }
 
@@ -99,6 +184,7 @@ impl std::default::Default for Parser<'_> {
     fn default() -> Self {
         Parser {
             known_addresses: HashMap::new(),
+            constants: HashMap::new(),
             current_pointer: 0x200, // Start of code in memory space
         }
     }
@@ -125,6 +211,30 @@ impl_from_str_radix!(u8);
 impl_from_str_radix!(u16);
 impl_from_str_radix!(u32);
 
+// Operands computed by expressions, negative values are written in two's
+// complement so `add v0, -1` decrements
+trait FromValue
+where
+    Self: Sized,
+{
+    fn from_value(value: i64) -> Option<Self>;
+}
+
+macro_rules! impl_from_value {
+    ($t: ty, $signed: ty) => {
+        impl FromValue for $t {
+            fn from_value(value: i64) -> Option<Self> {
+                <$t>::try_from(value)
+                    .ok()
+                    .or_else(|| <$signed>::try_from(value).ok().map(|value| value as $t))
+            }
+        }
+    };
+}
+
+impl_from_value!(u8, i8);
+impl_from_value!(u16, i16);
+
 fn parse_number<T>(number: &str) -> LineResult<T>
 where
     T: FromStrRadix + std::str::FromStr<Err = std::num::ParseIntError>,
@@ -145,10 +255,22 @@ impl<'a> Parser<'a> {
             return Err(LineError::InvalidAddress(instruction.to_string()));
         };
         let name = &instruction[..split_pos];
-        let instr = instruction[split_pos + 1..]
-            .trim()
-            .split_whitespace()
-            .map(|val| parse_number(val).map(Instruction::Raw))
+        let values = instruction[split_pos + 1..].trim();
+        // Values are separated by commas, plain numbers and names can also be
+        // separated by spaces, anything else is a single expression
+        let values: Vec<&str> = if values.contains(',') {
+            values.split(',').collect()
+        } else if values.split_whitespace().all(is_operand) {
+            values.split_whitespace().collect()
+        } else {
+            vec![values]
+        };
+        let instr = values
+            .into_iter()
+            .map(|val| {
+                self.parse_value(&val.trim().to_lowercase())
+                    .map(Instruction::Raw)
+            })
             .collect::<LineResult<Vec<Instruction>>>()?;
 
         Ok((name, instr))
@@ -184,20 +306,45 @@ impl<'a> Parser<'a> {
         Ok(first_instruction)
     }
 
-    fn parse_addr(&self, symbol: &str) -> LineResult<Addr> {
-        let address = self.known_addresses.get(symbol);
-
-        if let Some(location) = address {
-            Ok(*location as u32)
+    // Value of a constant or of a label
+    fn resolve(&self, name: &str, depth: usize) -> LineResult<i64> {
+        if let Some(expr) = self.constants.get(name) {
+            if depth >= MAX_CONSTANT_DEPTH {
+                return Err(LineError::InvalidExpression(name.to_string()));
+            }
+            evaluate(expr, &|name| self.resolve(name, depth + 1))
+        } else if let Some(location) = self.known_addresses.get(name) {
+            Ok(*location as i64)
         } else {
-            if let Some(slice) = symbol.strip_prefix("0x") {
-                u32::from_str_radix(slice, 16)
-            } else {
-                symbol
-                    .parse::<i32>()
-                    .map(|offset| (2 * offset + self.current_pointer as i32) as u32)
+            Err(LineError::UnknownSymbol(name.to_string()))
+        }
+    }
+
+    // Words starting with v are registers, unless a constant or a label has
+    // this name
+    fn is_register(&self, token: &str) -> bool {
+        token.starts_with('v')
+            && is_identifier(token)
+            && !self.constants.contains_key(token)
+            && !self.known_addresses.contains_key(token)
+    }
+
+    fn parse_value<T: FromValue>(&self, operand: &str) -> LineResult<T> {
+        let value = evaluate(operand, &|name| self.resolve(name, 0))?;
+        T::from_value(value).ok_or_else(|| LineError::ValueOutOfRange(operand.to_string()))
+    }
+
+    fn parse_addr(&self, symbol: &str) -> LineResult<Addr> {
+        // A bare decimal number is an offset in instructions from this one
+        if let Ok(offset) = symbol.parse::<i32>() {
+            return Ok((2 * offset + self.current_pointer as i32) as u32);
+        }
+        match evaluate(symbol, &|name| self.resolve(name, 0)) {
+            Ok(address) => {
+                u32::try_from(address).map_err(|_| LineError::ValueOutOfRange(symbol.to_string()))
             }
-            .map_err(|_| LineError::InvalidAddress(symbol.to_string()))
+            Err(LineError::UnknownSymbol(_)) => Err(LineError::InvalidAddress(symbol.to_string())),
+            Err(err) => Err(err),
         }
     }
 
@@ -228,14 +375,16 @@ impl<'a> Parser<'a> {
                 Ok(Disp(
                     parse_register(tokens[0])?,
                     parse_register(tokens[1])?,
-                    parse_number(tokens[2])?,
+                    self.parse_value(tokens[2])?,
                 ))
             }
             "ld" => {
                 assert_num_args(2, tokens.len())?;
                 match tokens[0] {
                     "i" => {
-                        if let Ok(val) = parse_number(tokens[1]) {
+                        if let Some(addr) = tokens[1].strip_prefix("long ") {
+                            Ok(LongSetAddr(self.parse_addr(addr.trim())?))
+                        } else if let Ok(val) = parse_number(tokens[1]) {
                             Ok(SetAddr(val))
                         } else {
                             Ok(SetAddr(self.parse_addr(tokens[1])?))
@@ -246,26 +395,32 @@ impl<'a> Parser<'a> {
                     "f" => Ok(FontLoad(parse_register(tokens[1])?)),
                     "b" => Ok(BCD(parse_register(tokens[1])?)),
                     "[i]" => Ok(MemDump(parse_register(tokens[1])?)),
+                    "hf" => Ok(BigFontLoad(parse_register(tokens[1])?)),
+                    "r" => Ok(SaveFlags(parse_register(tokens[1])?)),
+                    "pitch" => Ok(SetPitch(parse_register(tokens[1])?)),
                     _ => match tokens[1] {
                         "k" => Ok(GetKeyOp(parse_register(tokens[0])?)),
                         "dt" => Ok(GetTimer(parse_register(tokens[0])?)),
                         "[i]" => Ok(MemLoad(parse_register(tokens[0])?)),
-                        _ => match tokens[1].chars().next() {
-                            Some('v') => Ok(SetRg(
-                                parse_register(tokens[0])?,
-                                parse_register(tokens[1])?,
-                            )),
-                            _ => Ok(Set(parse_register(tokens[0])?, parse_number(tokens[1])?)),
-                        },
+                        "r" => Ok(LoadFlags(parse_register(tokens[0])?)),
+                        _ if self.is_register(tokens[1]) => Ok(SetRg(
+                            parse_register(tokens[0])?,
+                            parse_register(tokens[1])?,
+                        )),
+                        _ => Ok(Set(
+                            parse_register(tokens[0])?,
+                            self.parse_value(tokens[1])?,
+                        )),
                     },
                 }
             }
             "se" => {
                 assert_num_args(2, tokens.len())?;
                 let first_register = parse_register(tokens[0])?;
-                match tokens[1].chars().next() {
-                    Some('v') => Ok(IfEqRg(first_register, parse_register(tokens[1])?)),
-                    _ => Ok(IfEq(first_register, parse_number(tokens[1])?)),
+                if self.is_register(tokens[1]) {
+                    Ok(IfEqRg(first_register, parse_register(tokens[1])?))
+                } else {
+                    Ok(IfEq(first_register, self.parse_value(tokens[1])?))
                 }
             }
             "or" => {
@@ -283,9 +438,10 @@ impl<'a> Parser<'a> {
             "sne" => {
                 assert_num_args(2, tokens.len())?;
                 let first_register = parse_register(tokens[0])?;
-                match tokens[1].chars().next() {
-                    Some('v') => Ok(IfNeqRg(first_register, parse_register(tokens[1])?)),
-                    _ => Ok(IfNeq(first_register, parse_number(tokens[1])?)),
+                if self.is_register(tokens[1]) {
+                    Ok(IfNeqRg(first_register, parse_register(tokens[1])?))
+                } else {
+                    Ok(IfNeq(first_register, self.parse_value(tokens[1])?))
                 }
             }
             "jp" => match tokens.len() {
@@ -306,13 +462,14 @@ impl<'a> Parser<'a> {
                 assert_num_args(2, tokens.len())?;
                 match tokens[0] {
                     "i" => Ok(AddToI(parse_register(tokens[1])?)),
-                    _ => match tokens[1].chars().next() {
-                        Some('v') => Ok(AddRg(
-                            parse_register(tokens[0])?,
-                            parse_register(tokens[1])?,
-                        )),
-                        _ => Ok(Add(parse_register(tokens[0])?, parse_number(tokens[1])?)),
-                    },
+                    _ if self.is_register(tokens[1]) => Ok(AddRg(
+                        parse_register(tokens[0])?,
+                        parse_register(tokens[1])?,
+                    )),
+                    _ => Ok(Add(
+                        parse_register(tokens[0])?,
+                        self.parse_value(tokens[1])?,
+                    )),
                 }
             }
             "sub" => {
@@ -320,12 +477,12 @@ impl<'a> Parser<'a> {
                 Ok(Sub(parse_register(tokens[0])?, parse_register(tokens[1])?))
             }
             "shr" => {
-                assert_num_args(1, tokens.len())?;
-                Ok(RightShift(parse_register(tokens[0])?))
+                let (vx, vy) = parse_shift_registers(&tokens)?;
+                Ok(RightShift(vx, vy))
             }
             "shl" => {
-                assert_num_args(1, tokens.len())?;
-                Ok(LeftShift(parse_register(tokens[0])?))
+                let (vx, vy) = parse_shift_registers(&tokens)?;
+                Ok(LeftShift(vx, vy))
             }
             "cls" => {
                 assert_num_args(0, tokens.len())?;
@@ -333,7 +490,10 @@ impl<'a> Parser<'a> {
             }
             "rnd" => {
                 assert_num_args(2, tokens.len())?;
-                Ok(Rand(parse_register(tokens[0])?, parse_number(tokens[1])?))
+                Ok(Rand(
+                    parse_register(tokens[0])?,
+                    self.parse_value(tokens[1])?,
+                ))
             }
             "skp" => {
                 assert_num_args(1, tokens.len())?;
@@ -345,38 +505,85 @@ impl<'a> Parser<'a> {
             }
             "raw" => {
                 assert_num_args(1, tokens.len())?;
-                Ok(Raw(parse_number::<u16>(tokens[0])?))
+                Ok(Raw(self.parse_value::<u16>(tokens[0])?))
+            }
+            "scd" => {
+                assert_num_args(1, tokens.len())?;
+                // Only the low nibble of the opcode holds the number of lines
+                match self.parse_value(tokens[0])? {
+                    n if n <= 0xF => Ok(ScrollDown(n)),
+                    _ => Err(LineError::ValueOutOfRange(tokens[0].to_string())),
+                }
+            }
+            "scr" => {
+                assert_num_args(0, tokens.len())?;
+                Ok(ScrollRight)
+            }
+            "scl" => {
+                assert_num_args(0, tokens.len())?;
+                Ok(ScrollLeft)
+            }
+            "exit" => {
+                assert_num_args(0, tokens.len())?;
+                Ok(Exit)
+            }
+            "low" => {
+                assert_num_args(0, tokens.len())?;
+                Ok(LowRes)
+            }
+            "high" => {
+                assert_num_args(0, tokens.len())?;
+                Ok(HighRes)
+            }
+            "save" => {
+                assert_num_args(2, tokens.len())?;
+                Ok(SaveRange(
+                    parse_register(tokens[0])?,
+                    parse_register(tokens[1])?,
+                ))
+            }
+            "load" => {
+                assert_num_args(2, tokens.len())?;
+                Ok(LoadRange(
+                    parse_register(tokens[0])?,
+                    parse_register(tokens[1])?,
+                ))
+            }
+            "plane" => {
+                assert_num_args(1, tokens.len())?;
+                Ok(SelectPlane(self.parse_value(tokens[0])?))
+            }
+            "audio" => {
+                assert_num_args(0, tokens.len())?;
+                Ok(LoadAudio)
             }
             _ => Err(LineError::InstructionErr(instruction.to_string())),
         };
 
-        if res.is_ok() {
-            self.current_pointer += 2;
+        if let Ok(instr) = &res {
+            self.current_pointer += instr.size() as u32;
         }
         res
     }
 
     pub fn parse_code(&mut self, instructions: &[(usize, &'a str)]) -> Result<Vec<Instruction>> {
-        let mut seen = 0;
-        for (i, (ln, mem)) in instructions
-            .iter()
-            .filter(|(_, line)| !line.is_empty()) // TODO: Merge the two filters
-            .enumerate()
-            .filter(|(_, (_, line))| line.trim_end().ends_with(':'))
-        {
-            let addr_name = mem.trim();
-            let addr_name = &addr_name[..addr_name.len() - 1];
-
-            let res = self
-                .known_addresses
-                .insert(&addr_name, self.current_pointer as usize + 2 * i - seen);
+        let mut pointer = self.current_pointer as usize;
+        for (ln, mem) in instructions.iter().filter(|(_, line)| !line.is_empty()) {
+            let trim = mem.trim();
+            let addr_name = if let Some(addr_name) = trim.strip_suffix(':') {
+                addr_name
+            } else {
+                pointer += instruction_size(trim);
+                continue;
+            };
+
+            let res = self.known_addresses.insert(&addr_name, pointer);
             if res.is_some() {
                 return Err(ParserError::line(
                     *ln,
                     LineError::DuplicateAddress(addr_name.to_string()),
                 ));
             }
-            seen += 2;
         }
 
         instructions
@@ -396,14 +603,333 @@ impl<'a> Parser<'a> {
     }
 }
 
-pub fn parse(program: &str) -> Result<Vec<Instruction>> {
-    let program = program.trim();
-    let lines: Vec<(usize, &str)> = program
-        .split('\n')
+// Macros can use each other and files include each other, up to these depths
+const MAX_MACRO_DEPTH: usize = 32;
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// A file and line of the program in the same place
+#[derive(Debug, Clone)]
+struct Location {
+    file: Option<Rc<str>>,
+    number: usize,
+}
+
+// A line of the program once files are included and macros expanded, with
+// the file and line it comes from in the program or in the definition of a
+// macro, and the name and place of each use of a macro it was expanded from,
+// the outermost first
+#[derive(Debug, Clone)]
+struct SourceLine {
+    location: Location,
+    expansions: Vec<(String, Location)>,
+    text: String,
+}
+
+impl SourceLine {
+    fn error(&self, err: LineError) -> ParserError {
+        self.locate(ParserError::LineErr {
+            file: self.location.file.as_deref().map(String::from),
+            line_number: self.location.number,
+            error: err,
+        })
+    }
+
+    // Points an error on this line back to the uses of the macros
+    fn locate(&self, err: ParserError) -> ParserError {
+        self.expansions
+            .iter()
+            .rev()
+            .fold(err, |error, (name, location)| ParserError::MacroErr {
+                name: name.clone(),
+                file: location.file.as_deref().map(String::from),
+                line_number: location.number,
+                error: Box::new(error),
+            })
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    // Labels declared in the body, renamed on each expansion
+    labels: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+type Macros = HashMap<String, Macro>;
+
+fn is_operand(word: &str) -> bool {
+    word.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn is_identifier(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && word.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split(';').next().unwrap_or("").trim()
+}
+
+// Arguments of a directive like .include when code starts with it
+fn directive<'a>(code: &'a str, name: &str) -> Option<&'a str> {
+    match code.strip_prefix(name) {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => Some(rest.trim()),
+        _ => None,
+    }
+}
+
+// Replaces the whole words of line found in names
+fn substitute(line: &str, names: &HashMap<&str, String>) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut word = String::new();
+    for c in line.chars().chain(std::iter::once('\n')) {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        match names.get(word.as_str()) {
+            Some(value) => result.push_str(value),
+            None => result.push_str(&word),
+        }
+        word.clear();
+        result.push(c);
+    }
+    result.pop();
+    result
+}
+
+// Paths of included files are relative to the file including them
+fn include_path(file: Option<&str>, path: &str) -> String {
+    match file.and_then(|file| Path::new(file).parent()) {
+        Some(directory) => directory.join(path).to_string_lossy().into_owned(),
+        None => path.to_string(),
+    }
+}
+
+fn quoted(path: &str) -> Option<&str> {
+    path.strip_prefix('"')?.strip_suffix('"')
+}
+
+// Reads the lines of a program, replacing `.include "file.s"` with the lines
+// of the file and `.incbin "file.bin"` with its bytes, as raw instructions
+// or as the values of `name: .incbin "file.bin"` in the data section
+fn read_source(
+    program: &str,
+    file: Option<Rc<str>>,
+    files: &dyn FileResolver,
+    depth: usize,
+    source: &mut Vec<SourceLine>,
+) -> Result<()> {
+    for (i, text) in program.split('\n').enumerate() {
+        let line = SourceLine {
+            location: Location {
+                file: file.clone(),
+                number: i + 1,
+            },
+            expansions: vec![],
+            text: text.to_string(),
+        };
+        let code = strip_comment(text);
+        let (label, code) = match code.split_once(':') {
+            Some((label, rest)) if is_identifier(label.trim()) => (Some(label.trim()), rest.trim()),
+            _ => (None, code),
+        };
+        let (path, binary) = match (directive(code, ".include"), directive(code, ".incbin")) {
+            (Some(path), _) if label.is_none() => (path, false),
+            (_, Some(path)) => (path, true),
+            _ => {
+                source.push(line);
+                continue;
+            }
+        };
+
+        let path = quoted(path).ok_or_else(|| {
+            line.error(LineError::IncludeErr(
+                path.to_string(),
+                "the file name must be quoted".to_string(),
+            ))
+        })?;
+        let path = include_path(file.as_deref(), path);
+        let include_err = |reason: String| line.error(LineError::IncludeErr(path.clone(), reason));
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error(LineError::RecursiveInclude(path)));
+        }
+        let content = files
+            .read(&path)
+            .map_err(|err| include_err(err.to_string()))?;
+
+        if binary {
+            // Odd sizes are padded with a zero byte
+            let words = content
+                .chunks(2)
+                .map(|word| format!("0x{:02X}{:02X}", word[0], word.get(1).unwrap_or(&0)))
+                .collect::<Vec<String>>();
+            let texts = match label {
+                Some(label) => vec![format!("{}: {}", label, words.join(" "))],
+                None => words.iter().map(|word| format!("raw {}", word)).collect(),
+            };
+            source.extend(texts.into_iter().map(|text| SourceLine {
+                text,
+                ..line.clone()
+            }));
+        } else {
+            let content = String::from_utf8(content)
+                .map_err(|_| include_err("not valid UTF-8".to_string()))?;
+            read_source(&content, Some(path.into()), files, depth + 1, source)?;
+        }
+    }
+    Ok(())
+}
+
+// Takes the `.macro name arg1, arg2` ... `.endm` definitions out of the
+// lines of the program
+fn read_macros(lines: Vec<SourceLine>) -> Result<(Macros, Vec<SourceLine>)> {
+    let mut macros = HashMap::new();
+    let mut program = vec![];
+    let mut lines = lines.into_iter();
+    while let Some(line) = lines.next() {
+        let code = strip_comment(&line.text);
+        let header = match directive(code, ".macro") {
+            Some(header) => header,
+            None => {
+                program.push(line);
+                continue;
+            }
+        };
+
+        let (name, params) = header
+            .split_once(char::is_whitespace)
+            .unwrap_or((header, ""));
+        let params = params
+            .split(',')
+            .map(str::trim)
+            .filter(|param| !param.is_empty())
+            .map(String::from)
+            .collect::<Vec<String>>();
+        if !is_identifier(name) || !params.iter().all(|param| is_identifier(param)) {
+            return Err(line.error(LineError::InvalidMacro(code.to_string())));
+        }
+
+        let mut body = vec![];
+        loop {
+            let mut body_line = lines
+                .next()
+                .ok_or_else(|| line.error(LineError::UnterminatedMacro(name.to_string())))?;
+            let code = strip_comment(&body_line.text).to_string();
+            if code == ".endm" {
+                break;
+            } else if directive(&code, ".macro").is_some() {
+                return Err(body_line.error(LineError::InvalidMacro(code)));
+            }
+            body_line.text = code;
+            body.push(body_line);
+        }
+        let labels = body
+            .iter()
+            .filter_map(|line| line.text.split_once(':'))
+            .map(|(label, _)| label.trim())
+            .filter(|label| is_identifier(label))
+            .map(String::from)
+            .collect();
+
+        let definition = Macro {
+            params,
+            labels,
+            body,
+        };
+        if macros.insert(name.to_lowercase(), definition).is_some() {
+            return Err(line.error(LineError::DuplicateMacro(name.to_string())));
+        }
+    }
+    Ok((macros, program))
+}
+
+// Expands line if it uses a macro, replacing the parameters with the
+// arguments and giving the labels of the macro a unique name like loop@3
+fn expand_line(
+    macros: &Macros,
+    line: SourceLine,
+    expanded: &mut Vec<SourceLine>,
+    count: &mut usize,
+) -> Result<()> {
+    let code = strip_comment(&line.text);
+    let (name, args) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+    let definition = match macros.get(&name.to_lowercase()) {
+        Some(definition) => definition,
+        None => {
+            expanded.push(line);
+            return Ok(());
+        }
+    };
+    if line.expansions.len() >= MAX_MACRO_DEPTH {
+        return Err(line.error(LineError::RecursiveMacro(name.to_string())));
+    }
+    let args = match args.trim() {
+        "" => vec![],
+        args => args.split(',').map(str::trim).collect::<Vec<&str>>(),
+    };
+    assert_num_args(definition.params.len(), args.len()).map_err(|err| line.error(err))?;
+
+    *count += 1;
+    let mut names = definition
+        .params
+        .iter()
+        .map(String::as_str)
+        .zip(args.iter().map(|arg| arg.to_string()))
+        .collect::<HashMap<&str, String>>();
+    for label in &definition.labels {
+        names.insert(label, format!("{}@{}", label, count));
+    }
+    let mut expansions = line.expansions.clone();
+    expansions.push((name.to_string(), line.location.clone()));
+    for body in &definition.body {
+        let body_line = SourceLine {
+            location: body.location.clone(),
+            expansions: expansions.clone(),
+            text: substitute(&body.text, &names),
+        };
+        expand_line(macros, body_line, expanded, count)?;
+    }
+    Ok(())
+}
+
+fn expand_macros(lines: Vec<SourceLine>) -> Result<Vec<SourceLine>> {
+    let (macros, lines) = read_macros(lines)?;
+    let mut expanded = Vec::with_capacity(lines.len());
+    let mut count = 0;
+    for line in lines {
+        expand_line(&macros, line, &mut expanded, &mut count)?;
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+fn parse(program: &str) -> Result<Vec<Instruction>> {
+    parse_with_symbols(program, None, &crate::files::FileSystem)
+        .map(|(instructions, _)| instructions)
+}
+
+// Also returns the address of every label declared in the program. Files are
+// included with files, relative to the file of the program if it has one.
+// Lines of files are numbered as in an editor, the program given without a
+// file is trimmed first.
+pub fn parse_with_symbols(
+    program: &str,
+    file: Option<&str>,
+    files: &dyn FileResolver,
+) -> Result<(Vec<Instruction>, HashMap<String, Addr>)> {
+    let mut source = vec![];
+    match file {
+        Some(file) => read_source(program, Some(file.into()), files, 0, &mut source)?,
+        None => read_source(program.trim(), None, files, 0, &mut source)?,
+    }
+    let source = expand_macros(source)?;
+    // Lines are numbered by their index in the source until errors are located
+    let lines: Vec<(usize, &str)> = source
+        .iter()
         .enumerate()
-        .filter_map(|(idx, line)| {
-            let new_idx = idx + 1;
-            let trim = line.trim();
+        .filter_map(|(new_idx, line)| {
+            let trim = line.text.trim();
             let comment_pos = trim.find(';');
             match comment_pos {
                 // inline comments
@@ -414,6 +940,57 @@ pub fn parse(program: &str) -> Result<Vec<Instruction>> {
         })
         .collect();
 
+    parse_lines(&lines).map_err(|err| match err {
+        ParserError::LineErr {
+            line_number, error, ..
+        } => source[line_number].error(error),
+        err => err,
+    })
+}
+
+// Reads `.equ NAME value` and `NAME = value` constant definitions
+fn parse_constant(line: &str) -> Option<LineResult<(&str, &str)>> {
+    let line = line.trim();
+    let (name, value) = match directive(line, ".equ") {
+        Some(rest) => match rest.split_once(char::is_whitespace) {
+            Some((name, value)) => (name.trim_end_matches(','), value),
+            None => return Some(Err(LineError::InvalidExpression(line.to_string()))),
+        },
+        _ => line.split_once('=')?,
+    };
+    let (name, value) = (name.trim(), value.trim());
+    if !is_identifier(name) || value.is_empty() {
+        return Some(Err(LineError::InvalidExpression(line.to_string())));
+    }
+    Some(Ok((name, value)))
+}
+
+fn parse_lines(lines: &[(usize, &str)]) -> Result<(Vec<Instruction>, HashMap<String, Addr>)> {
+    // Constants are defined anywhere in the program, their lines are kept
+    // empty so that sections don't move
+    let mut constants = HashMap::new();
+    let mut program = Vec::with_capacity(lines.len());
+    for &(i, line) in lines {
+        match parse_constant(line) {
+            Some(Ok((name, value))) => {
+                let name = name.to_lowercase();
+                if parse_register(&name).is_ok() {
+                    return Err(ParserError::line(i, LineError::ConstantIsRegister(name)));
+                }
+                if constants
+                    .insert(name.clone(), value.to_lowercase())
+                    .is_some()
+                {
+                    return Err(ParserError::line(i, LineError::DuplicateConstant(name)));
+                }
+                program.push((i, ""));
+            }
+            Some(Err(err)) => return Err(ParserError::line(i, err)),
+            None => program.push((i, line)),
+        }
+    }
+    let lines = &program[..];
+
     let mut code_sections: HashMap<&str, usize> = lines
         .iter()
         .enumerate()
@@ -437,7 +1014,10 @@ pub fn parse(program: &str) -> Result<Vec<Instruction>> {
     };
     code_sections.remove("code");
 
-    let mut parser = Parser::default();
+    let mut parser = Parser {
+        constants,
+        ..Parser::default()
+    };
     let data_section = code_sections.get("data");
     let mut data_section_instructions = if let Some(data_section_start) = data_section {
         let data_section_lines = &lines[*data_section_start + 1..code_section_start - 1];
@@ -445,7 +1025,7 @@ pub fn parse(program: &str) -> Result<Vec<Instruction>> {
     } else {
         if !code_sections.is_empty() {
             return Err(ParserError::line(
-                *code_sections.values().next().unwrap(),
+                lines[*code_sections.values().next().unwrap()].0,
                 LineError::UnknownSection(code_sections.keys().next().unwrap().to_string()),
             ));
         }
@@ -458,7 +1038,12 @@ pub fn parse(program: &str) -> Result<Vec<Instruction>> {
 
     // TODO: Throw error if no instructions
     data_section_instructions.append(&mut code_section_instructions);
-    Ok(data_section_instructions)
+    let symbols = parser
+        .known_addresses
+        .iter()
+        .map(|(name, &addr)| (name.to_string(), addr as Addr))
+        .collect();
+    Ok((data_section_instructions, symbols))
 }
 
 #[cfg(test)]
@@ -489,6 +1074,7 @@ mod tests {
             "#,
         ) {
             Err(ParserError::LineErr {
+                file: None,
                 line_number: _,
                 error: LineError::UnknownSection(unkown_section),
             }) => {
@@ -512,6 +1098,7 @@ start:
             "#,
         ) {
             Err(ParserError::LineErr {
+                file: None,
                 line_number: _,
                 error: LineError::InvalidAddress(symbol),
             }) => {
@@ -636,10 +1223,67 @@ start:
         test_compile_to_bin("LD V0, [I]", 0xF065)?;
         test_compile_to_bin("LD B, V3", 0xF333)?;
         test_compile_to_bin("CLS", 0x00E0)?;
+        test_compile_to_bin("SHR V3", 0x8336)?;
+        test_compile_to_bin("SHL V3, V4", 0x834E)?;
 
         Ok(())
     }
 
+    #[test]
+    fn test_from_asm_to_bin_schip() -> std::result::Result<(), String> {
+        test_compile_to_bin("SCD 3", 0x00C3)?;
+        test_compile_to_bin("SCR", 0x00FB)?;
+        test_compile_to_bin("SCL", 0x00FC)?;
+        test_compile_to_bin("EXIT", 0x00FD)?;
+        test_compile_to_bin("LOW", 0x00FE)?;
+        test_compile_to_bin("HIGH", 0x00FF)?;
+        test_compile_to_bin("LD HF, V2", 0xF230)?;
+        test_compile_to_bin("LD R, V5", 0xF575)?;
+        test_compile_to_bin("LD V5, R", 0xF585)?;
+
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.parse_code(&[(1, "SCD 20")]),
+            Err(ParserError::line(
+                1,
+                LineError::ValueOutOfRange("20".to_string())
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_asm_xochip() -> std::result::Result<(), String> {
+        use Instruction::*;
+
+        test_compile("LD I, LONG 0x1234", LongSetAddr(0x1234))?;
+        test_compile("SAVE V1, V3", SaveRange(1, 3))?;
+        test_compile("LOAD V3, V1", LoadRange(3, 1))?;
+        test_compile("PLANE 2", SelectPlane(2))?;
+        test_compile("AUDIO", LoadAudio)?;
+        test_compile("LD PITCH, V4", SetPitch(4))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_addr_after_long() -> std::result::Result<(), String> {
+        let symbols = parse(
+            r#"
+.code
+    ld i, long addr
+addr:
+    jp addr
+            "#,
+        )
+        .map_err(|e| e.to_string())?;
+
+        assert_eq!(symbols[0], Instruction::LongSetAddr(0x204));
+        assert_eq!(symbols[1], Instruction::GoTo(0x204));
+        Ok(())
+    }
+
     #[test]
     fn test_parse_register_fail() {
         let expected: std::result::Result<usize, LineError> =
@@ -691,6 +1335,7 @@ start:
         assert_eq!(
             res,
             Err(ParserError::LineErr {
+                file: None,
                 line_number: 3,
                 error: LineError::RegisterErr("vkw".to_string()),
             })
@@ -711,6 +1356,7 @@ x: 0xffff
         assert_eq!(
             res,
             Err(ParserError::LineErr {
+                file: None,
                 line_number: 5,
                 error: LineError::DuplicateAddress("x".to_string()),
             })
@@ -731,9 +1377,291 @@ x:
         assert_eq!(
             res,
             Err(ParserError::LineErr {
+                file: None,
                 line_number: 8,
                 error: LineError::DuplicateAddress("x".to_string()),
             })
         );
     }
+
+    #[test]
+    fn test_macro() {
+        let program = r#"
+.macro sprite x, y, digit ; draws a digit
+    ld v0, digit
+    ld f, v0
+    drw x, y, 5
+.endm
+
+.macro wait_key key
+wait:
+    ld v0, key
+    sknp v0
+    jp wait
+    sprite v1, v2, key
+.endm
+
+.code
+    wait_key 1
+    wait_key 0x2
+"#;
+        let expected = r#"
+.code
+wait@1:
+    ld v0, 1
+    sknp v0
+    jp wait@1
+    ld v0, 1
+    ld f, v0
+    drw v1, v2, 5
+wait@3:
+    ld v0, 0x2
+    sknp v0
+    jp wait@3
+    ld v0, 0x2
+    ld f, v0
+    drw v1, v2, 5
+"#;
+        let (instructions, symbols) =
+            parse_with_symbols(program, None, &crate::files::FileSystem).unwrap();
+        assert_eq!(instructions, parse(expected).unwrap());
+        assert_eq!(symbols["wait@1"], 0x200);
+        assert_eq!(symbols["wait@3"], 0x20C);
+        assert!(!symbols.contains_key("wait"));
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let program = r#".macro draw x
+    drw x, v1, 5
+.endm
+
+.macro twice x
+    draw x
+    draw vz
+.endm
+
+.code
+    twice v0"#;
+        let err = parse(program).unwrap_err();
+        assert_eq!(
+            err,
+            ParserError::MacroErr {
+                name: "twice".to_string(),
+                file: None,
+                line_number: 11,
+                error: Box::new(ParserError::MacroErr {
+                    name: "draw".to_string(),
+                    file: None,
+                    line_number: 7,
+                    error: Box::new(ParserError::line(
+                        2,
+                        LineError::RegisterErr("vz".to_string())
+                    )),
+                }),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "line 11: in macro 'twice': line 7: in macro 'draw': line 2: invalid register 'vz'"
+        );
+
+        assert_eq!(
+            parse(".macro draw x\ndrw x, x, 5\n.endm\n.code\ndraw v0, v1"),
+            Err(ParserError::line(
+                5,
+                LineError::WrongNumberOfArguments(1, 2)
+            ))
+        );
+        assert_eq!(
+            parse(".code\n.macro loop\nloop\n"),
+            Err(ParserError::line(
+                2,
+                LineError::UnterminatedMacro("loop".to_string())
+            ))
+        );
+        assert!(matches!(
+            parse(".macro loop\nloop\n.endm\n.code\nloop"),
+            Err(ParserError::MacroErr { .. })
+        ));
+        assert_eq!(
+            parse(".macro 1x\n.endm\n.code"),
+            Err(ParserError::line(
+                1,
+                LineError::InvalidMacro(".macro 1x".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        use Instruction::*;
+
+        let instructions = parse(
+            r#"
+.equ SCREEN_W 64
+HALF = SCREEN_W / 2
+.data
+sprites: 0x1234, (HALF << 8) | 0xFF
+more: HALF + 1
+
+.code
+    ld v0, SCREEN_W - 8
+    ld i, sprites + 5 * 2
+    add v1, -1
+    se v2, HALF
+    drw v0, v1, HI(sprites) + lo(0x203)
+    jp end & 0xFFFF
+end:
+    ld i, long end + 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                GoTo(0x208),
+                Raw(0x1234),
+                Raw(0x20FF),
+                Raw(0x0021),
+                Set(0, 56),
+                SetAddr(0x20C),
+                Add(1, 0xFF),
+                IfEq(2, 32),
+                Disp(0, 1, 5),
+                GoTo(0x214),
+                LongSetAddr(0x216),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        assert_eq!(
+            parse(".code\nld v0, 0x80 * 2"),
+            Err(ParserError::line(
+                2,
+                LineError::ValueOutOfRange("0x80 * 2".to_string())
+            ))
+        );
+        assert_eq!(
+            parse(".code\nld v0, size + 1"),
+            Err(ParserError::line(
+                2,
+                LineError::UnknownSymbol("size".to_string())
+            ))
+        );
+        assert!(matches!(
+            parse("a = b\nb = a\n.code\nld v0, a"),
+            Err(ParserError::LineErr {
+                file: None,
+                line_number: 4,
+                error: LineError::InvalidExpression(..),
+            })
+        ));
+        assert_eq!(
+            parse("x = 1\n.equ x 2\n.code"),
+            Err(ParserError::line(
+                2,
+                LineError::DuplicateConstant("x".to_string())
+            ))
+        );
+        assert_eq!(
+            parse("VA = 1\n.code\nld va, 2"),
+            Err(ParserError::line(
+                1,
+                LineError::ConstantIsRegister("va".to_string())
+            ))
+        );
+        assert_eq!(
+            parse(".equ x\n.code"),
+            Err(ParserError::line(
+                1,
+                LineError::InvalidExpression(".equ x".to_string())
+            ))
+        );
+    }
+
+    fn files() -> HashMap<String, Vec<u8>> {
+        let mut files = HashMap::new();
+        files.insert(
+            "lib/draw.s".to_string(),
+            b".include \"macros.s\"\n\ndraw:\n    digit v0\n    ret".to_vec(),
+        );
+        files.insert(
+            "lib/macros.s".to_string(),
+            b".macro digit x\n    ld f, x\n    drw v1, v1, 5\n.endm".to_vec(),
+        );
+        files.insert("lib/loop.s".to_string(), b".include \"loop.s\"".to_vec());
+        files.insert("sprite.bin".to_string(), vec![0x18, 0x3C, 0x7E]);
+        files
+    }
+
+    #[test]
+    fn test_include() {
+        use Instruction::*;
+
+        let program = r#".data
+sprite: .incbin "sprite.bin"
+
+.code
+    call draw
+    .incbin "sprite.bin"
+    .include "lib/draw.s""#;
+        let (instructions, symbols) =
+            parse_with_symbols(program, Some("main.s"), &files()).unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                GoTo(0x206),
+                Raw(0x183C),
+                Raw(0x7E00),
+                Call(0x20C),
+                Raw(0x183C),
+                Raw(0x7E00),
+                FontLoad(0),
+                Disp(1, 1, 5),
+                Return,
+            ]
+        );
+        assert_eq!(symbols["draw"], 0x20C);
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let files = files();
+        let parse_main = |program: &str| parse_with_symbols(program, Some("main.s"), &files);
+        assert_eq!(
+            parse_main(".code\n.include \"lib/missing.s\"").unwrap_err(),
+            ParserError::LineErr {
+                file: Some("main.s".to_string()),
+                line_number: 2,
+                error: LineError::IncludeErr(
+                    "lib/missing.s".to_string(),
+                    "file not found".to_string()
+                ),
+            }
+        );
+        assert!(matches!(
+            parse_main(".code\n.include \"lib/loop.s\""),
+            Err(ParserError::LineErr {
+                error: LineError::RecursiveInclude(..),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_main(".code\n.include lib/draw.s"),
+            Err(ParserError::LineErr {
+                error: LineError::IncludeErr(..),
+                ..
+            })
+        ));
+
+        // Errors in macros give the files of the macro and of its use
+        let err = parse_main(".code\n.include \"lib/macros.s\"\ndigit vz").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "main.s:3: in macro 'digit': lib/macros.s:2: invalid register 'vz'"
+        );
+    }
 }
@@ -0,0 +1,61 @@
+use std::fs::File;
+use std::io::{BufWriter, Error as IOError};
+use std::path::Path;
+
+use crate::emu::Proc;
+use crate::error::{ChipoError, Result};
+use crate::palette::Palette;
+
+// Size of the high resolution screen, lower resolutions are scaled up to it
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+
+// Pixels as plane indices, sized for the high resolution at the given scale
+// so that the size doesn't change with the resolution
+pub fn screen_image(proc: &Proc, scale: usize) -> (usize, usize, Vec<u8>) {
+    let (width, height) = (WIDTH * scale, HEIGHT * scale);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = y * proc.height() / height * proc.width();
+        for x in 0..width {
+            pixels.push(proc.pixels[row + x * proc.width() / width] & 0x3);
+        }
+    }
+    (width, height, pixels)
+}
+
+// Writes the screen to an RGB PNG file, sized as screen_image
+pub fn write_png(proc: &Proc, path: &Path, scale: usize, palette: &Palette) -> Result<()> {
+    let (width, height, pixels) = screen_image(proc, scale);
+    let data = pixels
+        .iter()
+        .flat_map(|&planes| palette.color(planes))
+        .collect::<Vec<u8>>();
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(|err| ChipoError::IOError(IOError::other(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_screen_image() {
+        // The top left low resolution pixel covers 2x2 high resolution ones
+        let mut proc = Proc::binary(&[]).unwrap();
+        proc.pixels[0] = 1;
+        proc.pixels[65] = 3;
+        let (width, height, pixels) = screen_image(&proc, 2);
+        assert_eq!((width, height), (256, 128));
+        assert_eq!(pixels[..5], [1, 1, 1, 1, 0]);
+        assert_eq!(pixels[width * 3], 1);
+        assert_eq!(pixels[width * 4 + 4], 3);
+    }
+}